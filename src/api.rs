@@ -0,0 +1,86 @@
+use serde::Deserialize;
+
+use crate::http::RequestError;
+use crate::media::Media;
+use crate::thread_ref::ThreadRef;
+
+/// Thread as returned by the 4chan JSON API
+#[derive(Debug, Clone, Deserialize)]
+pub struct Thread {
+    pub posts: Vec<Post>,
+}
+
+/// Post as returned by the 4chan JSON API, see https://github.com/4chan/4chan-API
+#[derive(Debug, Clone, Deserialize)]
+pub struct Post {
+    pub no: u64,
+    pub time: i64,
+    pub name: Option<String>,
+    pub trip: Option<String>,
+    /// Poster ID, only given on boards with IDs enabled
+    pub id: Option<String>,
+    pub sub: Option<String>,
+    /// Comment as HTML
+    pub com: Option<String>,
+    pub tim: Option<u64>,
+    /// Original name of the attached file without extension
+    pub filename: Option<String>,
+    pub ext: Option<String>,
+    pub fsize: Option<u64>,
+    /// Base64 encoded MD5 hash of the attached file
+    pub md5: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default)]
+    pub filedeleted: u8,
+    #[serde(default)]
+    pub spoiler: u8,
+}
+
+impl Thread {
+    /// Returns the post with the file at the given link attached
+    pub fn post_with_file(&self, link: &str) -> Option<&Post> {
+        let media = Media::from_link(link)?;
+        self.posts.iter().find(|p| p.tim == Some(media.tim))
+    }
+}
+
+impl Post {
+    /// File attached to the post, `None` if the post has no file
+    pub fn media(&self) -> Option<Media> {
+        Some(Media {
+            tim: self.tim?,
+            ext: self.ext.clone()?,
+            thumbnail: false,
+        })
+    }
+}
+
+/// Returns the board and thread number of a link to a 4chan thread like `https://boards.4chan.org/g/thread/123`.
+/// `None` if the link is not to a 4chan thread
+pub fn board_and_thread(url: &str) -> Option<(String, u64)> {
+    ThreadRef::parse(url).ok()
+        .filter(|t| t.is_4chan())
+        .map(|t| (t.board, t.no))
+}
+
+/// Returns the board of a link to a thread like `https://boards.4chan.org/g/thread/123`, which also works for archive sites
+pub fn board(url: &str) -> Option<String> {
+    ThreadRef::parse(url).ok().map(|t| t.board)
+}
+
+/// Returns the link to the JSON API of a 4chan thread
+pub fn thread_api_link(board: &str, thread: u64) -> String {
+    format!("https://a.4cdn.org/{}/thread/{}.json", board, thread)
+}
+
+/// Returns the thread from the 4chan JSON API
+pub fn get_thread(board: &str, thread: u64) -> Result<Thread, RequestError> {
+    let url = thread_api_link(board, thread);
+    let resp = crate::get_response(&url)?;
+
+    serde_json::from_reader(resp).map_err(|e| {
+        crate::debug_output("json error", &format!("{} returned {}", url, e));
+        RequestError::Invalid { url, error: e.to_string() }
+    })
+}
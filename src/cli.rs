@@ -14,29 +14,179 @@ pub fn build_cli() -> App<'static, 'static> {
                 .global(true))
             .arg(Arg::with_name("not-numbered")
                 .long("not-numbered")
+                .overrides_with("numbered")
                 .help("Do not print image number in output")
                 .global(true))
+            .arg(Arg::with_name("numbered")
+                .long("numbered")
+                .overrides_with("not-numbered")
+                .help("Print image number in output, overrides not_numbered in the configuration")
+                .global(true))
             .arg(Arg::with_name("update-modify-date")
                 .short("u")
                 .long("update-modify-date")
+                .overrides_with("no-update-modify-date")
                 .global(true)
                 .help("Updates modify date of existing images")
                 .long_help(
                     "Updates the modify date to the current time when downloading images.\nThis ensures that images will be in order of time posted when sorted by modification date"))
+            .arg(Arg::with_name("no-update-modify-date")
+                .long("no-update-modify-date")
+                .overrides_with("update-modify-date")
+                .global(true)
+                .help("Do not update modify dates, overrides update_modify_date in the configuration"))
+            .arg(Arg::with_name("dedupe")
+                .long("dedupe")
+                .value_name("POLICY")
+                .possible_values(&["hardlink", "symlink", "skip"])
+                .takes_value(true)
+                .global(true)
+                .help("Link or skip files already downloaded to another thread directory")
+                .long_help(
+                    "Checks the hashes of downloaded files against all files downloaded before, which are kept in hashes.json.\nDuplicates are replaced with a hard link or symbolic link to the earlier file, or not kept at all with 'skip'. Skipped files are recorded in hashes.json so they are not downloaded again"))
+            .arg(Arg::with_name("root")
+                .long("root")
+                .value_name("DIRECTORY")
+                .env("CHAN_ROOT")
+                .takes_value(true)
+                .global(true)
+                .help("Directory holding the watch list and all thread directories, the current directory by default"))
+            .arg(Arg::with_name("layout")
+                .long("layout")
+                .value_name("LAYOUT")
+                .possible_values(&["flat", "nested"])
+                .takes_value(true)
+                .global(true)
+                .help("Save new threads to '{thread} - {subject}' or to '{board}/{thread} - {subject}' in the root"))
+            .arg(Arg::with_name("dir-template")
+                .long("dir-template")
+                .value_name("TEMPLATE")
+                .takes_value(true)
+                .global(true)
+                .help("Directory of new threads in the root, like '{board}/{no} - {subject}'")
+                .long_help(
+                    "Directory of new threads in the root, with {board}, {no} and {subject} replaced by the board, thread number and subject.\nEach '/' separated part is a directory. Overrides --layout"))
+            .arg(Arg::with_name("portable-names")
+                .long("portable-names")
+                .overrides_with("no-portable-names")
+                .global(true)
+                .help("Make directory names safe on Windows, exFAT and NTFS")
+                .long_help(
+                    "Replaces characters which are not allowed on Windows, exFAT and NTFS, removes trailing dots and spaces and renames reserved names like CON"))
+            .arg(Arg::with_name("no-portable-names")
+                .long("no-portable-names")
+                .overrides_with("portable-names")
+                .global(true)
+                .help("Keep characters in directory names, overrides portable_names in the configuration"))
+            .arg(Arg::with_name("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .takes_value(true)
+                .global(true)
+                .help("Use the settings of a [profile.<PROFILE>] section in the configuration files")
+                .long_help(
+                    "Uses the settings of a [profile.<PROFILE>] section in the configuration files.\nSettings are read from ~/.config/chan/config.toml and chan.toml in the current directory, which overrides the former.\nCommand line options override all settings"))
+            .arg(Arg::with_name("retries")
+                .long("retries")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .global(true)
+                .help("Times a failed request is retried, 3 by default")
+                .long_help(
+                    "Times a request is retried after a timeout, a connection error or a 429 or 5xx status, 3 by default.\nThe wait between retries is doubled each time, or as long as the site asks for with Retry-After"))
+            .arg(Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .global(true)
+                .help("Files of a thread downloaded at the same time, 1 by default")
+                .long_help(
+                    "Files of a thread downloaded at the same time, 1 by default.\nRate limits, --limit-rate and quotas are shared by all jobs, and each file is printed when it is done"))
+            .arg(Arg::with_name("limit-rate")
+                .long("limit-rate")
+                .value_name("SIZE")
+                .takes_value(true)
+                .global(true)
+                .help("Bytes per second all downloads may use together, like 500K")
+                .long_help(
+                    "Bytes per second all downloads may use together, like 500K or 2M. 0 does not limit downloads.\nLower limits for some hours of the day are set with [[quiet_hours]] in the configuration files"))
+            .arg(Arg::with_name("user-agent")
+                .long("user-agent")
+                .value_name("USER_AGENT")
+                .takes_value(true)
+                .global(true)
+                .help("User agent sent with all requests, a Firefox user agent by default"))
+            .arg(Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .takes_value(true)
+                .global(true)
+                .help("Send requests through an HTTP or SOCKS5 proxy, like socks5h://localhost:1080")
+                .long_help(
+                    "Sends requests through an HTTP or SOCKS5 proxy, like http://localhost:8080 or socks5h://localhost:1080.\nsocks5h resolves host names on the proxy. Hosts with their own proxy in [proxies] in the configuration files still use it"))
+            .arg(Arg::with_name("header")
+                .long("header")
+                .value_name("HEADER")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Extra header sent with all requests, like 'Referer: https://boards.4chan.org/'. Can be given more than once"))
+            .arg(Arg::with_name("search-provider")
+                .long("search-provider")
+                .value_name("URL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Site searched for images with --iqdb, like https://3d.iqdb.org. Can be given more than once")
+                .long_help(
+                    "Site searched for images with --iqdb instead of https://iqdb.org, which must work like iqdb.org such as https://3d.iqdb.org.\nCan be given more than once, sites are searched in order until one finds matches"))
+            .arg(Arg::with_name("cookies")
+                .long("cookies")
+                .value_name("FILE")
+                .takes_value(true)
+                .global(true)
+                .help("Send cookies from a Netscape cookies.txt file, as exported by browsers")
+                .long_help(
+                    "Sends cookies from a Netscape cookies.txt file, as exported by browsers or written by curl and wget.\nCookies are only sent to the sites they belong to, like a session cookie of an archive or a 4chan pass"))
+            .arg(Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .possible_values(&["always", "never", "auto"])
+                .default_value("auto")
+                .global(true)
+                .help("Color the output, 'auto' only colors it when written to a terminal"))
             .subcommand(SubCommand::with_name("download")
                 .alias("d")
                 .about("Downloads all images on given thread")
+                    .arg(Arg::with_name("output")
+                        .long("output")
+                        .value_name("FORMAT")
+                        .possible_values(&["text", "json", "ndjson"])
+                        .default_value("text")
+                        .help("Write events of downloaded files and threads as JSON")
+                        .long_help(
+                            "Writes an event for each file (queued, exists, downloaded, failed, iqdb-not-found) and each thread to standard output.\n'json' writes an array of all events when done, 'ndjson' writes one event per line as they happen.\nMessages are written to standard error instead"))
                     .arg(Arg::with_name("iqdb")
                         .short("i")
                         .long("iqdb")
                         .help("Gather hi-res images from iqdb.org from archive sites"))
+                    .arg(Arg::with_name("keep-original")
+                        .short("k")
+                        .long("keep-original")
+                        .requires("iqdb")
+                        .help("Keep the original images and save iqdb images to an 'iqdb' subfolder")
+                        .long_help(
+                            "Downloads the original images from the thread and saves the images found on iqdb.org to an 'iqdb' subfolder.\nThe source page, similarity, dimensions of both images and the distance between their perceptual hashes are recorded in 'iqdb/sources.txt'"))
                     .arg(Arg::with_name("directory")
                         .short("d")
                         .long("dir")
                         .value_name("DIRECTORY")
                         .conflicts_with("name")
                         .takes_value(true)
-                        .help("Save files to <DIRECTORY>"))
+                        .help("Save files to <DIRECTORY>, which replaces the last part of the directory template"))
                     .arg(Arg::with_name("name")
                         .short("n")
                         .long("name")
@@ -44,6 +194,99 @@ pub fn build_cli() -> App<'static, 'static> {
                         .conflicts_with("directory")
                         .takes_value(true)
                         .help("Save files to '<thread_id> - <NAME>'"))
+                    .arg(Arg::with_name("save-posts")
+                        .short("p")
+                        .long("save-posts")
+                        .overrides_with("no-save-posts")
+                        .help("Save all posts of the thread to thread.json")
+                        .long_help(
+                            "Saves all posts of the thread with their comments, replies and attachment info to thread.json in the thread directory.\nThe file is kept up to date when running 'update'"))
+                    .arg(Arg::with_name("no-save-posts")
+                        .long("no-save-posts")
+                        .overrides_with("save-posts")
+                        .help("Do not save posts, overrides save_posts in the configuration"))
+                    .arg(Arg::with_name("snapshot")
+                        .short("s")
+                        .long("snapshot")
+                        .overrides_with("no-snapshot")
+                        .help("Save an offline HTML snapshot of the thread to thread.html")
+                        .long_help(
+                            "Saves a self-contained HTML page of the thread to thread.html in the thread directory, linking to the downloaded images.\nThe snapshot is regenerated when posts or downloaded files change when running 'update'"))
+                    .arg(Arg::with_name("no-snapshot")
+                        .long("no-snapshot")
+                        .overrides_with("snapshot")
+                        .help("Do not save a snapshot, overrides snapshot in the configuration"))
+                    .arg(Arg::with_name("thumbnails")
+                        .short("t")
+                        .long("thumbnails")
+                        .overrides_with("no-thumbnails")
+                        .conflicts_with("iqdb")
+                        .help("Save thumbnails of all images to a 'thumbs' subfolder")
+                        .long_help(
+                            "Saves the thumbnail of every image to a 'thumbs' subfolder in the thread directory, which snapshots use as previews.\nThumbnails are kept up to date when running 'update'"))
+                    .arg(Arg::with_name("no-thumbnails")
+                        .long("no-thumbnails")
+                        .overrides_with("thumbnails")
+                        .help("Do not save thumbnails, overrides thumbnails in the configuration"))
+                    .arg(Arg::with_name("only")
+                        .long("only")
+                        .value_name("EXTENSIONS")
+                        .takes_value(true)
+                        .help("Only download files with the given comma separated extensions, e.g. 'webm,gif'"))
+                    .arg(Arg::with_name("exclude-ext")
+                        .long("exclude-ext")
+                        .value_name("EXTENSIONS")
+                        .takes_value(true)
+                        .help("Do not download files with the given comma separated extensions"))
+                    .arg(Arg::with_name("min-size")
+                        .long("min-size")
+                        .value_name("SIZE")
+                        .takes_value(true)
+                        .help("Only download files of at least <SIZE>, e.g. '500K' or '2M'"))
+                    .arg(Arg::with_name("max-size")
+                        .long("max-size")
+                        .value_name("SIZE")
+                        .takes_value(true)
+                        .help("Only download files of at most <SIZE>, e.g. '500K' or '2M'"))
+                    .arg(Arg::with_name("min-width")
+                        .long("min-width")
+                        .value_name("PIXELS")
+                        .takes_value(true)
+                        .help("Only download images at least <PIXELS> wide"))
+                    .arg(Arg::with_name("min-height")
+                        .long("min-height")
+                        .value_name("PIXELS")
+                        .takes_value(true)
+                        .help("Only download images at least <PIXELS> high"))
+                    .arg(Arg::with_name("op-only")
+                        .long("op-only")
+                        .help("Only download files from the opening post"))
+                    .arg(Arg::with_name("poster-id")
+                        .long("poster-id")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .help("Only download files from posts by poster <ID>"))
+                    .arg(Arg::with_name("tripcode")
+                        .long("tripcode")
+                        .value_name("TRIPCODE")
+                        .takes_value(true)
+                        .help("Only download files from posts with <TRIPCODE>"))
+                    // --name is already used for the directory name
+                    .arg(Arg::with_name("poster-name")
+                        .long("poster-name")
+                        .value_name("POSTER_NAME")
+                        .takes_value(true)
+                        .help("Only download files from posts by <POSTER_NAME>"))
+                    .arg(Arg::with_name("comment-match")
+                        .long("comment-match")
+                        .value_name("REGEX")
+                        .takes_value(true)
+                        .help("Only download files from posts with a comment matching <REGEX>"))
+                    .arg(Arg::with_name("min-replies")
+                        .long("min-replies")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Only download files from posts with at least <N> replies"))
                     .arg(Arg::with_name("override")
                         .short("o")
                         .long("override")
@@ -53,12 +296,150 @@ pub fn build_cli() -> App<'static, 'static> {
                         .short("q")
                         .long("quiet")
                         .help("Disables output"))
+                    .arg(Arg::with_name("input-file")
+                        .long("input-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Download all threads in <FILE>, with one link per line. '-' reads links from standard input"))
+                    .arg(Arg::with_name("url")
+                        .help("Links to 4chan threads, '-' reads links from standard input")
+                        .multiple(true)
+                        .required_unless("input-file")))
+            .subcommand(SubCommand::with_name("dedupe")
+                .about("Replaces identical files in thread directories with links to one of them")
+                    .arg(Arg::with_name("policy")
+                        .long("policy")
+                        .value_name("POLICY")
+                        .possible_values(&["hardlink", "symlink", "skip"])
+                        .default_value("hardlink")
+                        .help("Replace duplicates with hard links or symbolic links, or remove them with 'skip'"))
+                    .arg(Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only print the duplicates found"))
+                    .arg(Arg::with_name("directories")
+                        .value_name("DIRECTORY")
+                        .multiple(true)
+                        .help("Directories to check, all watched thread directories if not given")))
+            .subcommand(SubCommand::with_name("list")
+                .alias("ls")
+                .about("Lists watched threads with their status, number of files, disk usage and last update"))
+            .subcommand(SubCommand::with_name("add")
+                .about("Adds a thread to the watch list without downloading it")
+                    .arg(Arg::with_name("directory")
+                        .short("d")
+                        .long("dir")
+                        .value_name("DIRECTORY")
+                        .takes_value(true)
+                        .help("Save files to <DIRECTORY>, which replaces the last part of the directory template"))
                     .arg(Arg::with_name("url")
-                        .help("Link to 4chan thread")
-                        .required(true)))
+                        .value_name("URL")
+                        .required(true)
+                        .help("Url of the thread")))
+            .subcommand(SubCommand::with_name("remove")
+                .alias("rm")
+                .about("Removes a thread from the watch list")
+                    .arg(Arg::with_name("delete-files")
+                        .long("delete-files")
+                        .help("Delete the directory of the thread as well"))
+                    .arg(Arg::with_name("thread")
+                        .value_name("THREAD")
+                        .required(true)
+                        .help("Url, thread number or directory of the thread")))
+            .subcommand(SubCommand::with_name("pause")
+                .about("Stops updating a thread with 'update' while keeping it in the watch list")
+                    .arg(Arg::with_name("thread")
+                        .value_name("THREAD")
+                        .required(true)
+                        .help("Url, thread number or directory of the thread")))
+            .subcommand(SubCommand::with_name("resume")
+                .about("Updates a paused thread with 'update' again")
+                    .arg(Arg::with_name("thread")
+                        .value_name("THREAD")
+                        .required(true)
+                        .help("Url, thread number or directory of the thread")))
+            .subcommand(SubCommand::with_name("export")
+                .about("Writes the watch list to a file or standard output")
+                    .arg(Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "csv", "urls"])
+                        .default_value("json")
+                        .help("Format of the exported watch list"))
+                    .arg(Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Write to <FILE> instead of standard output")))
+            .subcommand(SubCommand::with_name("import")
+                .about("Adds the threads in an exported watch list which are not watched yet")
+                    .arg(Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "csv", "urls", "legacy"])
+                        .takes_value(true)
+                        .help("Format of the watch list, detected from its contents if not given")
+                        .long_help(
+                            "Format of the watch list, detected from its contents if not given.\n'legacy' is the '{url};{directory}' format of threads.txt. Threads in a list of urls are named with the directory template"))
+                    .arg(Arg::with_name("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Watch list to import, '-' for standard input")))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Moves the directory of a watched thread and updates the watch list")
+                    .arg(Arg::with_name("from-subject")
+                        .long("from-subject")
+                        .conflicts_with("new-name")
+                        .help("Name the directory after the current subject of the thread using the directory template"))
+                    .arg(Arg::with_name("thread")
+                        .value_name("THREAD")
+                        .required(true)
+                        .help("Url, thread number or directory of the thread"))
+                    .arg(Arg::with_name("new-name")
+                        .value_name("NEW_NAME")
+                        .required_unless("from-subject")
+                        .help("New directory name, a path relative to the root if it contains '/'")))
+            .subcommand(SubCommand::with_name("migrate")
+                .about("Moves thread directories in the root to the nested '{board}/{thread} - {subject}' layout")
+                    .arg(Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only print the directories which would be moved")))
+            .subcommand(SubCommand::with_name("similar")
+                .about("Finds images in thread directories which look alike, such as resized or re-encoded reposts")
+                    .arg(Arg::with_name("algorithm")
+                        .long("algorithm")
+                        .value_name("ALGORITHM")
+                        .possible_values(&["ahash", "dhash", "phash"])
+                        .default_value("phash")
+                        .help("Perceptual hash used to compare images"))
+                    .arg(Arg::with_name("distance")
+                        .long("distance")
+                        .value_name("BITS")
+                        .default_value("8")
+                        .help("Maximum number of differing bits between hashes of similar images"))
+                    .arg(Arg::with_name("keep-best")
+                        .long("keep-best")
+                        .help("Remove all images but the one with the highest resolution in each group"))
+                    .arg(Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only print the images which would be removed with --keep-best"))
+                    .arg(Arg::with_name("directories")
+                        .value_name("DIRECTORY")
+                        .multiple(true)
+                        .help("Directories to check, all watched thread directories if not given")))
             .subcommand(SubCommand::with_name("update")
                 .alias("u")
-                .about("Downloads new images from watched threads from the threads.txt file in current directory"))
+                .about("Downloads new images from watched threads in the threads.json file in the root")
+                    .arg(Arg::with_name("output")
+                        .long("output")
+                        .value_name("FORMAT")
+                        .possible_values(&["text", "json", "ndjson"])
+                        .default_value("text")
+                        .help("Write events of downloaded files and threads as JSON")
+                        .long_help(
+                            "Writes an event for each file (queued, exists, downloaded, failed, iqdb-not-found) and each thread to standard output.\n'json' writes an array of all events when done, 'ndjson' writes one event per line as they happen.\nMessages are written to standard error instead")))
                 .arg(Arg::with_name("print-existing-images")
                     .short("e")
                     .long("print-existing-images")
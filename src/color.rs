@@ -0,0 +1,67 @@
+use ansi_term::{ANSIString, Style};
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// When output is colored, given with `--color`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum When {
+    Always,
+    Never,
+    /// Only when the output is a terminal
+    Auto,
+}
+
+impl FromStr for When {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(When::Always),
+            "never" => Ok(When::Never),
+            "auto" => Ok(When::Auto),
+            _ => Err(format!("'{}' is not a color option, expected always, never or auto", s)),
+        }
+    }
+}
+
+/// Enables or disables colors for all output, `is_terminal` is whether messages are written to a terminal
+pub fn set(when: When, is_terminal: bool) {
+    let enabled = match when {
+        When::Always => true,
+        When::Never => false,
+        When::Auto => is_terminal,
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Colors used in messages, which are left out when colors are disabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Purple,
+    Cyan,
+}
+
+impl Color {
+    /// Paints the text in the color, or returns it as it is when colors are disabled
+    pub fn paint<'a, I: Into<Cow<'a, str>>>(self, input: I) -> ANSIString<'a> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Style::new().paint(input);
+        }
+        let color = match self {
+            Color::Red => ansi_term::Color::Red,
+            Color::Green => ansi_term::Color::Green,
+            Color::Yellow => ansi_term::Color::Yellow,
+            Color::Blue => ansi_term::Color::Blue,
+            Color::Purple => ansi_term::Color::Purple,
+            Color::Cyan => ansi_term::Color::Cyan,
+        };
+        color.paint(input)
+    }
+}
@@ -0,0 +1,259 @@
+use crate::color::Color::*;
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::filter::{Filters, deserialize_size};
+use crate::library::Layout;
+use crate::quota;
+
+/// Configuration file in the current directory, overriding the global configuration
+pub const LOCAL_FILE_NAME: &str = "chan.toml";
+
+/// Settings used by the current run, set once in `main`
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Defaults for command line options. Options not given in any configuration file are `None`.
+/// Unknown keys are errors, so a misspelled option is not silently ignored
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Directory holding the watch list and all thread directories
+    pub root: Option<PathBuf>,
+    pub layout: Option<Layout>,
+    /// Template for the directory of new threads like `{board}/{no} - {subject}`, overrides the layout
+    pub dir_template: Option<String>,
+    /// Make directory names safe on Windows, exFAT and NTFS
+    pub portable_names: Option<bool>,
+    pub user_agent: Option<String>,
+    /// Proxy of all hosts, like `http://localhost:8080` or `socks5h://localhost:1080`
+    pub proxy: Option<String>,
+    /// Proxy of each host and its subdomains, `none` connects to a host directly
+    pub proxies: HashMap<String, String>,
+    /// Extra headers sent to each host and its subdomains by header name, `*` sends headers to all hosts
+    pub headers: HashMap<String, HashMap<String, String>>,
+    /// Netscape cookies.txt file with cookies sent to the sites they belong to
+    pub cookies: Option<PathBuf>,
+    /// Seconds to wait for a connection
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for a response or for more data of it, iqdb.org is always waited for
+    #[serde(alias = "timeout")]
+    pub read_timeout: Option<u64>,
+    /// Seconds a whole request may take including the download, not limited by default
+    pub total_timeout: Option<u64>,
+    /// Timeouts of each host and its subdomains, overriding the timeouts above
+    pub timeouts: HashMap<String, Timeouts>,
+    /// Sites searched for images with `--iqdb` in order until one finds matches, iqdb.org by default.
+    /// The sites must work like iqdb.org, such as 3d.iqdb.org
+    pub search_providers: Vec<String>,
+    /// Files of a thread downloaded at the same time, 1 by default
+    pub jobs: Option<usize>,
+    /// Times a request is retried after a timeout, a connection error, a 429 or a 5xx status
+    pub retries: Option<u32>,
+    /// Seconds before the first retry, doubled for each following retry
+    pub retry_delay: Option<f64>,
+    /// Longest wait in seconds between retries, also when a site asks for a longer wait with Retry-After
+    pub max_retry_delay: Option<f64>,
+    /// Requests per second to each host and its subdomains, 0 turns off the default limit of 4chan
+    pub rate_limits: HashMap<String, f64>,
+    /// Bytes per second all downloads may use together, like `500K`. 0 does not limit downloads
+    #[serde(deserialize_with = "deserialize_size")]
+    pub limit_rate: Option<u64>,
+    /// Hours of the day with a lower limit than `limit_rate`
+    pub quiet_hours: Vec<QuietHours>,
+    /// Free space to leave on the disk of the root, downloads are stopped before going below it. Not checked by default
+    #[serde(deserialize_with = "deserialize_size")]
+    pub disk_reserve: Option<u64>,
+    /// Largest size of the files of each thread
+    #[serde(deserialize_with = "deserialize_size")]
+    pub thread_quota: Option<u64>,
+    /// Largest size of all files in the root
+    #[serde(deserialize_with = "deserialize_size")]
+    pub library_quota: Option<u64>,
+    pub quota_policy: Option<quota::Policy>,
+    pub dedupe: Option<String>,
+    pub not_numbered: Option<bool>,
+    pub update_modify_date: Option<bool>,
+    pub save_posts: Option<bool>,
+    pub snapshot: Option<bool>,
+    pub thumbnails: Option<bool>,
+    /// Filters applied to every thread in addition to the filters of the thread
+    pub filters: Filters,
+}
+
+/// Timeouts in seconds of requests to a host, 0 turns a timeout off
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub connect: Option<u64>,
+    pub read: Option<u64>,
+    pub total: Option<u64>,
+}
+
+/// Hours from `start` until `end` in local time when downloads are limited to `limit_rate` bytes per second.
+/// Hours ending before they start last past midnight
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+    #[serde(deserialize_with = "deserialize_size")]
+    pub limit_rate: Option<u64>,
+}
+
+impl QuietHours {
+    /// Returns true if the time is within the hours
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        }
+        else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Reads a time of day like `22:00`
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("'{}' is not a time, expected a time like 22:00", time)))
+}
+
+/// Contents of a configuration file, named profiles are selected with `--profile`
+#[derive(Debug, Default)]
+struct ConfigFile {
+    settings: Settings,
+    profile: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+    /// Reads the profiles apart from the other settings, since unknown keys of flattened structs are not reported by serde
+    fn parse(contents: &str) -> Result<ConfigFile, toml::de::Error> {
+        let mut table: toml::Table = toml::from_str(contents)?;
+        let profile = match table.remove("profile") {
+            Some(profile) => HashMap::deserialize(profile)?,
+            None => HashMap::new(),
+        };
+        Ok(ConfigFile {
+            settings: Settings::deserialize(toml::Value::Table(table))?,
+            profile,
+        })
+    }
+}
+
+impl Settings {
+    /// Returns the settings with options which are not set taken from `defaults`
+    pub fn or(self, defaults: Settings) -> Settings {
+        Settings {
+            root: self.root.or(defaults.root),
+            layout: self.layout.or(defaults.layout),
+            dir_template: self.dir_template.or(defaults.dir_template),
+            portable_names: self.portable_names.or(defaults.portable_names),
+            user_agent: self.user_agent.or(defaults.user_agent),
+            proxy: self.proxy.or(defaults.proxy),
+            proxies: defaults.proxies.into_iter().chain(self.proxies).collect(),
+            headers: merge_headers(defaults.headers, self.headers),
+            cookies: self.cookies.or(defaults.cookies),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            read_timeout: self.read_timeout.or(defaults.read_timeout),
+            total_timeout: self.total_timeout.or(defaults.total_timeout),
+            timeouts: defaults.timeouts.into_iter().chain(self.timeouts).collect(),
+            search_providers: if self.search_providers.is_empty() { defaults.search_providers } else { self.search_providers },
+            jobs: self.jobs.or(defaults.jobs),
+            retries: self.retries.or(defaults.retries),
+            retry_delay: self.retry_delay.or(defaults.retry_delay),
+            max_retry_delay: self.max_retry_delay.or(defaults.max_retry_delay),
+            rate_limits: defaults.rate_limits.into_iter().chain(self.rate_limits).collect(),
+            limit_rate: self.limit_rate.or(defaults.limit_rate),
+            quiet_hours: if self.quiet_hours.is_empty() { defaults.quiet_hours } else { self.quiet_hours },
+            disk_reserve: self.disk_reserve.or(defaults.disk_reserve),
+            thread_quota: self.thread_quota.or(defaults.thread_quota),
+            library_quota: self.library_quota.or(defaults.library_quota),
+            quota_policy: self.quota_policy.or(defaults.quota_policy),
+            dedupe: self.dedupe.or(defaults.dedupe),
+            not_numbered: self.not_numbered.or(defaults.not_numbered),
+            update_modify_date: self.update_modify_date.or(defaults.update_modify_date),
+            save_posts: self.save_posts.or(defaults.save_posts),
+            snapshot: self.snapshot.or(defaults.snapshot),
+            thumbnails: self.thumbnails.or(defaults.thumbnails),
+            filters: self.filters.or(defaults.filters),
+        }
+    }
+}
+
+/// Returns the headers of both settings, headers in `headers` replace headers with the same name and host in `defaults`
+fn merge_headers(mut defaults: HashMap<String, HashMap<String, String>>, headers: HashMap<String, HashMap<String, String>>) -> HashMap<String, HashMap<String, String>> {
+    for (host, headers) in headers {
+        defaults.entry(host).or_default().extend(headers);
+    }
+    defaults
+}
+
+/// Path of the global configuration file, `$XDG_CONFIG_HOME/chan/config.toml` or `~/.config/chan/config.toml`
+pub fn global_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("chan").join("config.toml"))
+}
+
+/// Reads the global and the local configuration file and returns the settings of the profile.
+/// The local file overrides the global file, and a profile overrides the settings outside of profiles
+pub fn load(profile: Option<&str>) -> Settings {
+    let global = global_path().map(|p| read(&p)).unwrap_or_default();
+    let local = read(Path::new(LOCAL_FILE_NAME));
+
+    let mut settings = local.settings.or(global.settings);
+    if let Some(profile) = profile {
+        settings = match (local.profile.get(profile), global.profile.get(profile)) {
+            (None, None) => {
+                statusln!("{} Profile '{}' is not defined in any configuration file", Red.paint("Error:"), profile);
+                std::process::exit(1);
+            },
+            (local, global) => local.cloned().unwrap_or_default()
+                .or(global.cloned().unwrap_or_default())
+                .or(settings),
+        };
+    }
+    if let Err(e) = settings.filters.validate() {
+        statusln!("{} Could not read the filters in the configuration files: {}", Red.paint("Error:"), e);
+        std::process::exit(1);
+    }
+    settings
+}
+
+/// Reads a configuration file, an empty configuration is returned if it does not exist
+fn read(path: &Path) -> ConfigFile {
+    let contents = match read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => return ConfigFile::default(),
+    };
+    crate::debug_output("config", &path.display().to_string());
+    ConfigFile::parse(&contents).unwrap_or_else(|e| {
+        statusln!("{} Could not read {}: {}", Red.paint("Error:"), path.display(), e);
+        std::process::exit(1);
+    })
+}
+
+/// Sets the settings used by the current run
+pub fn set(settings: Settings) {
+    SETTINGS.set(settings).expect("Settings are already set");
+}
+
+/// Returns the settings used by the current run
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// Returns the directory holding the watch list and all thread directories
+pub fn root() -> &'static Path {
+    settings().root.as_deref().unwrap_or_else(|| Path::new("."))
+}
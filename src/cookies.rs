@@ -0,0 +1,102 @@
+use reqwest::Url;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Cookies sent with requests, loaded once in `main`
+static COOKIES: OnceLock<Vec<Cookie>> = OnceLock::new();
+
+/// Cookie from a Netscape `cookies.txt` file, as exported by browsers and written by curl and wget
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    /// Host the cookie is sent to, without a leading dot
+    pub domain: String,
+    /// Also send the cookie to subdomains of `domain`
+    pub include_subdomains: bool,
+    pub path: String,
+    /// Only send the cookie over HTTPS
+    pub secure: bool,
+    /// Seconds since the Unix epoch when the cookie expires, 0 for cookies which last for the session
+    pub expires: u64,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    /// Returns true if the cookie is sent with a request to the url at the given time
+    fn matches(&self, url: &Url, now: u64) -> bool {
+        let host = match url.host_str() {
+            Some(h) => h.to_lowercase(),
+            None => return false,
+        };
+        let domain_matches = host == self.domain
+            || (self.include_subdomains && host.ends_with(&format!(".{}", self.domain)));
+        // A path of /a matches /a and /a/b but not /ab
+        let path_matches = url.path() == self.path
+            || (url.path().starts_with(&self.path) && (self.path.ends_with('/') || url.path()[self.path.len()..].starts_with('/')));
+
+        domain_matches
+            && path_matches
+            && (!self.secure || url.scheme() == "https")
+            && (self.expires == 0 || self.expires > now)
+    }
+}
+
+/// Parses a Netscape cookie file. Each line has the domain, whether subdomains are included,
+/// the path, whether the cookie is secure, the expiry date, the name and the value separated by tabs
+pub fn parse(contents: &str) -> Result<Vec<Cookie>, String> {
+    let mut cookies = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        // curl marks cookies which are hidden from scripts with this prefix, other lines starting with # are comments
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 {
+            return Err(format!("line {} has {} fields separated by tabs, expected 7", i + 1, fields.len()));
+        }
+        let expires = fields[4].parse::<u64>()
+            .map_err(|_| format!("line {}: '{}' is not an expiry date", i + 1, fields[4]))?;
+        let domain = fields[0].to_lowercase();
+        cookies.push(Cookie {
+            // Domains starting with a dot are also sent to subdomains by older files
+            include_subdomains: fields[1].eq_ignore_ascii_case("TRUE") || domain.starts_with('.'),
+            domain: domain.trim_start_matches('.').to_string(),
+            path: fields[2].to_string(),
+            secure: fields[3].eq_ignore_ascii_case("TRUE"),
+            expires,
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+        });
+    }
+    Ok(cookies)
+}
+
+/// Reads the cookie file and sends its cookies with all following requests. A leading `~` is the home directory
+pub fn load(path: &Path) -> Result<usize, String> {
+    let path = match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    };
+    let contents = read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let cookies = parse(&contents).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let count = cookies.len();
+    COOKIES.set(cookies).expect("Cookies are already loaded");
+    Ok(count)
+}
+
+/// Returns the value of the `Cookie` header for a request to the url, `None` if no cookie is sent
+pub fn header(url: &str) -> Option<String> {
+    let cookies = COOKIES.get()?;
+    let url = Url::parse(url).ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let value = cookies.iter()
+        .filter(|c| c.matches(&url, now))
+        .map(|c| format!("{}={}", c.name, c.value))
+        .collect::<Vec<_>>()
+        .join("; ");
+    if value.is_empty() { None } else { Some(value) }
+}
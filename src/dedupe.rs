@@ -0,0 +1,401 @@
+use crate::color::Color::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, hard_link, read_dir, read_to_string, remove_file, rename, symlink_metadata, write};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// File in the root holding the hashes of all downloaded files
+pub const FILE_NAME: &str = "hashes.json";
+
+/// What to do with a file which has already been downloaded to another directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Hardlink,
+    Symlink,
+    /// Do not keep the duplicate
+    Skip,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardlink" => Ok(Policy::Hardlink),
+            "symlink" => Ok(Policy::Symlink),
+            "skip" => Ok(Policy::Skip),
+            _ => Err(format!("'{}' is not a dedupe policy, expected hardlink, symlink or skip", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHashes {
+    /// Base64 encoded MD5 hash, the same format as given by the 4chan API
+    pub md5: String,
+    /// Hex encoded SHA-256 hash
+    pub sha256: String,
+}
+
+/// Hashes of all downloaded files
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashIndex {
+    files: BTreeMap<PathBuf, FileHashes>,
+    /// Files which were not kept with the skip policy, with the file each is identical to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    skipped: BTreeMap<PathBuf, PathBuf>,
+    #[serde(skip)]
+    by_md5: HashMap<String, PathBuf>,
+    #[serde(skip)]
+    by_sha256: HashMap<String, PathBuf>,
+}
+
+impl HashIndex {
+    /// Reads the index from `hashes.json`, an empty index is returned if it does not exist
+    pub fn load() -> HashIndex {
+        let mut index: HashIndex = match read_to_string(crate::config::root().join(FILE_NAME)) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                crate::debug_output("json error", &format!("Could not read {}: {}", FILE_NAME, e));
+                HashIndex::default()
+            }),
+            Err(_) => HashIndex::default(),
+        };
+        let files = std::mem::take(&mut index.files);
+        for (path, hashes) in files {
+            index.add(path, hashes);
+        }
+        index
+    }
+
+    pub fn save(&self) {
+        write(crate::config::root().join(FILE_NAME), serde_json::to_string_pretty(self).unwrap()).expect("Could not write hashes.json");
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+
+    pub fn insert(&mut self, path: PathBuf, hashes: FileHashes) {
+        let path = normalize(&path);
+        self.skipped.remove(&path);
+        self.add(path, hashes);
+    }
+
+    /// Records that a file was not kept since it is identical to `original`
+    pub fn skip(&mut self, path: &Path, original: &Path) {
+        self.skipped.insert(normalize(path), normalize(original));
+    }
+
+    /// Returns the file a skipped file is identical to, if it still exists
+    pub fn skipped(&self, path: &Path) -> Option<PathBuf> {
+        self.skipped.get(&normalize(path))
+            .map(|original| crate::config::root().join(original))
+            .filter(|original| original.is_file())
+    }
+
+    /// Adds a file with a path which is already relative to the root
+    fn add(&mut self, path: PathBuf, hashes: FileHashes) {
+        self.by_md5.entry(hashes.md5.clone()).or_insert_with(|| path.clone());
+        self.by_sha256.entry(hashes.sha256.clone()).or_insert_with(|| path.clone());
+        self.files.insert(path, hashes);
+    }
+
+    /// Updates the paths of all files in a directory which has been moved
+    pub fn move_dir(&mut self, old: &Path, new: &Path) {
+        let (old, new) = (normalize(old), normalize(new));
+        let moved = |path: PathBuf| match path.strip_prefix(&old) {
+            Ok(rest) => new.join(rest),
+            Err(_) => path,
+        };
+        let files = std::mem::take(&mut self.files);
+        self.by_md5.clear();
+        self.by_sha256.clear();
+        for (path, hashes) in files {
+            self.add(moved(path), hashes);
+        }
+        self.skipped = std::mem::take(&mut self.skipped).into_iter()
+            .map(|(path, original)| (moved(path), moved(original)))
+            .collect();
+    }
+
+    /// Replaces the files in the directories with the files of an index built from them, files in other directories are kept
+    pub fn replace_dirs(&mut self, dirs: &[PathBuf], scanned: HashIndex) {
+        let dirs: Vec<PathBuf> = dirs.iter().map(|d| normalize(d)).collect();
+        let files = std::mem::take(&mut self.files);
+        self.by_md5.clear();
+        self.by_sha256.clear();
+        for (path, hashes) in files {
+            if !dirs.iter().any(|d| path.starts_with(d)) {
+                self.add(path, hashes);
+            }
+        }
+        for (path, hashes) in scanned.files {
+            self.add(path, hashes);
+        }
+        self.skipped.extend(scanned.skipped);
+    }
+
+    /// Returns an indexed file other than `except` with the given MD5 hash, if it still exists
+    pub fn find_md5(&self, md5: &str, except: &Path) -> Option<PathBuf> {
+        self.find(self.by_md5.get(md5), except, |h| h.md5 == md5)
+    }
+
+    /// Returns an indexed file other than `except` with the given SHA-256 hash, if it still exists
+    pub fn find_sha256(&self, sha256: &str, except: &Path) -> Option<PathBuf> {
+        self.find(self.by_sha256.get(sha256), except, |h| h.sha256 == sha256)
+    }
+
+    fn find<F: Fn(&FileHashes) -> bool>(&self, first: Option<&PathBuf>, except: &Path, matches: F) -> Option<PathBuf> {
+        let except = normalize(except);
+        // The first file with the hash may have been removed, then any other file with the hash is used
+        first.into_iter()
+            .chain(self.files.iter().filter(|(_, h)| matches(h)).map(|(p, _)| p))
+            .map(|p| (p, crate::config::root().join(p)))
+            .find(|(p, path)| **p != except && path.is_file())
+            .map(|(_, path)| path)
+    }
+}
+
+/// Returns the path relative to the root, or the absolute path for files outside of the root,
+/// so the same file always has the same path in the index
+fn normalize(path: &Path) -> PathBuf {
+    let root = crate::config::root();
+    let path = if path.is_absolute() || root.is_relative() {
+        path.to_path_buf()
+    }
+    else {
+        std::env::current_dir().map(|d| d.join(path)).unwrap_or_else(|_| path.to_path_buf())
+    };
+    let path: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+    let root: PathBuf = root.components().filter(|c| *c != Component::CurDir).collect();
+    match path.strip_prefix(&root) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+/// Links or skips files which have already been downloaded to another directory
+pub struct Deduplicator {
+    pub policy: Policy,
+    pub index: HashIndex,
+}
+
+impl Deduplicator {
+    pub fn new(policy: Policy) -> Deduplicator {
+        Deduplicator {
+            policy,
+            index: HashIndex::load(),
+        }
+    }
+
+    /// Returns the already downloaded file `file_path` is identical to if it was skipped before.
+    /// Files without a hash from the 4chan API, such as files on archive sites, are only known to be duplicates this way
+    pub fn skipped(&self, file_path: &Path) -> Option<PathBuf> {
+        if self.policy != Policy::Skip {
+            return None;
+        }
+        self.index.skipped(file_path)
+    }
+
+    /// Links `file_path` to an already downloaded file with the MD5 hash given by 4chan.
+    /// Returns the already downloaded file, `None` if there is none and the file has to be downloaded
+    pub fn link_known(&mut self, md5: &str, file_path: &Path) -> Option<PathBuf> {
+        let original = self.index.find_md5(md5, file_path)?;
+        if let Err(e) = link(&original, file_path, self.policy) {
+            crate::debug_output("link error", &format!("{} -> {}: {}", file_path.display(), original.display(), e));
+            return None;
+        }
+        if self.policy != Policy::Skip {
+            let hashes = self.index.files[&normalize(&original)].clone();
+            self.index.insert(file_path.to_path_buf(), hashes);
+        }
+        Some(original)
+    }
+
+    /// Adds a downloaded file to the index. If an identical file has been downloaded before the file is replaced according to the policy.
+    /// Returns the file downloaded before if the file is a duplicate
+    pub fn add(&mut self, file_path: &Path) -> Option<PathBuf> {
+        if self.index.contains(file_path) || !file_path.is_file() {
+            return None;
+        }
+        let hashes = match hash_file(file_path) {
+            Ok(h) => h,
+            Err(e) => {
+                crate::debug_output("hash error", &format!("{}: {}", file_path.display(), e));
+                return None;
+            }
+        };
+
+        // Files which are already links to the same file are not duplicates
+        let original = self.index.find_sha256(&hashes.sha256, file_path)
+            .filter(|original| !same_file(original, file_path));
+        if let Some(original) = &original {
+            match link(original, file_path, self.policy) {
+                Err(e) => crate::debug_output("link error", &format!("{} -> {}: {}", file_path.display(), original.display(), e)),
+                // Skipped files are recorded so they are not downloaded again
+                Ok(_) if self.policy == Policy::Skip => self.index.skip(file_path, original),
+                Ok(_) => (),
+            }
+        }
+        if file_path.is_file() {
+            self.index.insert(file_path.to_path_buf(), hashes);
+        }
+        original
+    }
+
+    pub fn save(&self) {
+        self.index.save();
+    }
+}
+
+/// Returns the MD5 and SHA-256 hashes of the file
+pub fn hash_file(path: &Path) -> io::Result<FileHashes> {
+    let mut file = File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha256 = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let n = io::Read::read(&mut file, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buffer[..n]);
+        sha256.update(&buffer[..n]);
+    }
+
+    Ok(FileHashes {
+        md5: STANDARD.encode(md5.finalize()),
+        sha256: sha256.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+    })
+}
+
+/// Replaces `duplicate` with a link to `original` according to the policy, or removes it when skipping
+pub fn link(original: &Path, duplicate: &Path, policy: Policy) -> io::Result<()> {
+    if policy == Policy::Skip {
+        if symlink_metadata(duplicate).is_ok() {
+            remove_file(duplicate)?;
+        }
+        return Ok(());
+    }
+
+    // The link is created next to the duplicate first, which keeps the duplicate if linking fails
+    let name = duplicate.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = duplicate.with_file_name(format!(".{}.link", name));
+    if symlink_metadata(&tmp).is_ok() {
+        remove_file(&tmp)?;
+    }
+    match policy {
+        Policy::Hardlink => hard_link(original, &tmp)?,
+        // Absolute target so the link works regardless of where the directories are
+        _ => symlink(&original.canonicalize()?, &tmp)?,
+    }
+    rename(&tmp, duplicate)
+}
+
+#[cfg(unix)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Returns true if both paths are the same file, such as hard links to the same file
+#[cfg(unix)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    a.canonicalize().ok() == b.canonicalize().ok()
+}
+
+/// Returns all downloaded files in the directory and its subfolders, symbolic links are left out
+pub fn media_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let entries = match read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return files,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            files.append(&mut media_files(&path));
+        }
+        else if file_type.is_file() && crate::is_image_link(&path.to_string_lossy()) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Replaces files in the directories which are identical to an earlier file in them with links according to the policy.
+/// The files of the directories are indexed again, files of other directories are kept in the index
+pub fn consolidate(dirs: &[PathBuf], policy: Policy, dry_run: bool) {
+    let mut index = HashIndex::default();
+    let mut duplicates: u64 = 0;
+    let mut freed: u64 = 0;
+
+    for file in dirs.iter().flat_map(|d| media_files(d)) {
+        let hashes = match hash_file(&file) {
+            Ok(h) => h,
+            Err(e) => {
+                println!("{} Could not read {}: {}", Yellow.paint("Warning:"), file.display(), e);
+                continue;
+            }
+        };
+
+        if let Some(original) = index.find_sha256(&hashes.sha256, &file) {
+            if !same_file(&original, &file) {
+                let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                print!("{} is a duplicate of {} ", file.display(), original.display());
+                if dry_run {
+                    println!("{}", Blue.paint("(dry run)"));
+                }
+                else {
+                    match link(&original, &file, policy) {
+                        Ok(_) => {
+                            println!("{}", Green.paint("Done"));
+                            if policy == Policy::Skip {
+                                index.skip(&file, &original);
+                            }
+                        },
+                        Err(e) => {
+                            println!("{} {}", Red.paint("Failed:"), e);
+                            continue;
+                        }
+                    }
+                }
+                duplicates += 1;
+                freed += size;
+            }
+        }
+        if dry_run || file.is_file() {
+            index.insert(file, hashes);
+        }
+    }
+
+    println!("Found {} duplicates taking up {:.1} MB", duplicates, freed as f64 / (1024.0 * 1024.0));
+    if !dry_run {
+        let mut stored = HashIndex::load();
+        stored.replace_dirs(dirs, index);
+        stored.save();
+    }
+}
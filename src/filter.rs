@@ -0,0 +1,293 @@
+use clap::ArgMatches;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+use crate::api::{Post, Thread};
+use crate::media::Media;
+use crate::posts::{comment_text, reply_links};
+
+/// Filters for which files are downloaded from a thread, stored with the watched thread so `update` respects them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// Only download files with these extensions, all supported extensions if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+    /// Never download files with these extensions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_ext: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_size")]
+    pub min_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_height: Option<u32>,
+    /// Only download files posted in the opening post
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub op_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poster_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tripcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Regular expression the comment of the post must match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_match: Option<String>,
+    /// Minimum number of posts replying to the post, called `replied_to_by` in older watch lists
+    #[serde(skip_serializing_if = "Option::is_none", alias = "replied_to_by")]
+    pub min_replies: Option<usize>,
+}
+
+impl Filters {
+    /// Reads filters from the arguments of the `download` subcommand
+    pub fn from_args(args: &ArgMatches) -> Result<Filters, String> {
+        let size = |name: &str| args.value_of(name).map(parse_size).transpose();
+        let pixels = |name: &str| args.value_of(name)
+            .map(|n| n.parse::<u32>().map_err(|_| format!("'{}' is not a valid number of pixels", n)))
+            .transpose();
+
+        let comment_match = args.value_of("comment-match").map(|n| n.to_string());
+        let min_replies = args.value_of("min-replies")
+            .map(|n| n.parse::<usize>().map_err(|_| format!("'{}' is not a valid number of replies", n)))
+            .transpose()?;
+
+        let filters = Filters {
+            only: args.value_of("only").map(parse_extensions).unwrap_or_default(),
+            exclude_ext: args.value_of("exclude-ext").map(parse_extensions).unwrap_or_default(),
+            min_size: size("min-size")?,
+            max_size: size("max-size")?,
+            min_width: pixels("min-width")?,
+            min_height: pixels("min-height")?,
+            op_only: args.is_present("op-only"),
+            poster_id: args.value_of("poster-id").map(|n| n.to_string()),
+            tripcode: args.value_of("tripcode").map(|n| n.trim_start_matches('!').to_string()),
+            name: args.value_of("poster-name").map(|n| n.to_string()),
+            comment_match,
+            min_replies,
+        };
+        filters.validate()?;
+        Ok(filters)
+    }
+
+    /// Checks that the filters can be used, since filters are also read from the configuration files and the watch list
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(pattern) = &self.comment_match {
+            Regex::new(pattern).map_err(|e| format!("Invalid comment regex: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the filters with filters which are not set taken from `defaults`
+    pub fn or(self, defaults: Filters) -> Filters {
+        let list = |list: Vec<String>, default: Vec<String>| if list.is_empty() { default } else { list };
+        Filters {
+            only: list(self.only, defaults.only),
+            exclude_ext: list(self.exclude_ext, defaults.exclude_ext),
+            min_size: self.min_size.or(defaults.min_size),
+            max_size: self.max_size.or(defaults.max_size),
+            min_width: self.min_width.or(defaults.min_width),
+            min_height: self.min_height.or(defaults.min_height),
+            op_only: self.op_only || defaults.op_only,
+            poster_id: self.poster_id.or(defaults.poster_id),
+            tripcode: self.tripcode.or(defaults.tripcode),
+            name: self.name.or(defaults.name),
+            comment_match: self.comment_match.or(defaults.comment_match),
+            min_replies: self.min_replies.or(defaults.min_replies),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Filters::default()
+    }
+
+    /// Returns true if the filters need file metadata or posts from the 4chan API
+    pub fn needs_metadata(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.min_width.is_some() || self.min_height.is_some() ||
+            self.filters_posts()
+    }
+
+    /// Returns true if any filter applies to the posts files are attached to
+    pub fn filters_posts(&self) -> bool {
+        self.op_only ||
+            self.poster_id.is_some() ||
+            self.tripcode.is_some() ||
+            self.name.is_some() ||
+            self.comment_match.is_some() ||
+            self.min_replies.is_some()
+    }
+
+    /// Returns a function telling if the file at a link should be downloaded.
+    /// Posts in `thread` are used for filters on metadata and posts. Filters on metadata pass when the post of a file is not known,
+    /// filters on posts do not, so files are not downloaded when it is not known who posted them
+    pub fn matcher<'a>(&'a self, thread: Option<&'a Thread>) -> impl Fn(&str) -> bool + 'a {
+        let comment_regex = self.comment_match.as_ref().and_then(|n| Regex::new(n).ok());
+        let op = thread.and_then(|t| t.posts.first()).map(|p| p.no);
+
+        // Number of posts replying to each post
+        let mut reply_counts: HashMap<u64, usize> = HashMap::new();
+        if let (Some(thread), Some(_)) = (thread, self.min_replies) {
+            for post in thread.posts.iter() {
+                for no in reply_links(post.com.as_deref().unwrap_or_default()) {
+                    *reply_counts.entry(no).or_insert(0) += 1;
+                }
+            }
+        }
+
+        move |link: &str| {
+            let post = thread.and_then(|t| t.post_with_file(link));
+            if !self.allows(link, post) {
+                return false;
+            }
+            match post {
+                _ if !self.filters_posts() => true,
+                Some(post) => self.allows_post(post, op, &reply_counts, comment_regex.as_ref()),
+                None => false,
+            }
+        }
+    }
+
+    /// Returns true if the post passes all filters on posts
+    fn allows_post(&self, post: &Post, op: Option<u64>, reply_counts: &HashMap<u64, usize>, comment_regex: Option<&Regex>) -> bool {
+        let differs = |filter: &Option<String>, value: Option<&str>| match filter {
+            Some(filter) => value != Some(filter.as_str()),
+            None => false,
+        };
+
+        if self.op_only && op != Some(post.no) {
+            return false;
+        }
+        if differs(&self.poster_id, post.id.as_deref()) ||
+            differs(&self.tripcode, post.trip.as_deref().map(|t| t.trim_start_matches('!'))) ||
+            differs(&self.name, post.name.as_deref()) {
+            return false;
+        }
+        // Comments do not match regexes which are not valid
+        match (&self.comment_match, comment_regex) {
+            (Some(_), None) => return false,
+            (_, Some(regex)) if !regex.is_match(&comment_text(post.com.as_deref().unwrap_or_default())) => return false,
+            _ => (),
+        }
+        if let Some(min_replies) = self.min_replies {
+            if reply_counts.get(&post.no).copied().unwrap_or(0) < min_replies {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns true if the file at the link passes the filters on files.
+    /// `post` is the post the file is attached to, filters on metadata pass when it is not known
+    fn allows(&self, link: &str, post: Option<&Post>) -> bool {
+        // Thumbnails are always jpg images, so the extension of the original is only known from the post
+        let ext = match (post.and_then(|p| p.ext.clone()), Media::from_link(link)) {
+            (Some(ext), _) => Some(ext),
+            (None, Some(media)) if media.thumbnail => None,
+            (None, _) => link.rsplit_once('.').map(|(_, ext)| format!(".{}", ext)),
+        };
+        if let Some(ext) = ext {
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            if !self.only.is_empty() && !self.only.contains(&ext) {
+                return false;
+            }
+            if self.exclude_ext.contains(&ext) {
+                return false;
+            }
+        }
+
+        if let Some(post) = post {
+            let less = |a: Option<u64>, b: Option<u64>| matches!((a, b), (Some(a), Some(b)) if a < b);
+            // Smaller than the minimum size or larger than the maximum size
+            if less(post.fsize, self.min_size) || less(self.max_size, post.fsize) {
+                return false;
+            }
+            if less(post.w.map(u64::from), self.min_width.map(u64::from)) ||
+                less(post.h.map(u64::from), self.min_height.map(u64::from)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Parses a comma separated list of extensions like `webm,.GIF` to `["webm", "gif"]`
+fn parse_extensions(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+/// Parses a size like `500K`, `2M`, `1G` or a number of bytes
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.to_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("'{}' is not a valid size", size)),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n >= 0.0 => Ok((n * multiplier as f64) as u64),
+        _ => Err(format!("'{}' is not a valid size", size)),
+    }
+}
+
+/// Reads a size given either as a number of bytes or as a size like `500K`
+pub fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(n) => Ok(Some(n)),
+        Size::Text(text) => parse_size(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Thread where post 2 has a file and is replied to by posts 3 and 4
+    fn thread() -> Thread {
+        serde_json::from_str(r##"{"posts": [
+            {"no": 1, "time": 0, "com": "op"},
+            {"no": 2, "time": 0, "tim": 1620000000000, "ext": ".jpg"},
+            {"no": 3, "time": 0, "com": "<a href=\"#p2\" class=\"quotelink\">&gt;&gt;2</a>"},
+            {"no": 4, "time": 0, "com": "<a href=\"#p2\" class=\"quotelink\">&gt;&gt;2</a> <a href=\"#p2\" class=\"quotelink\">&gt;&gt;2</a>"}
+        ]}"##).unwrap()
+    }
+
+    #[test]
+    fn min_replies_counts_each_replying_post_once() {
+        let thread = thread();
+        let link = "https://i.4cdn.org/g/1620000000000.jpg";
+        let filters = |min_replies| Filters { min_replies: Some(min_replies), ..Filters::default() };
+
+        assert!(filters(1).matcher(Some(&thread))(link));
+        // Exactly as many replies as needed
+        assert!(filters(2).matcher(Some(&thread))(link));
+        assert!(!filters(3).matcher(Some(&thread))(link));
+    }
+
+    #[test]
+    fn min_replies_is_read_from_older_watch_lists() {
+        let filters: Filters = serde_json::from_str(r#"{"replied_to_by": 2}"#).unwrap();
+        assert_eq!(filters.min_replies, Some(2));
+        assert_eq!(serde_json::to_string(&filters).unwrap(), r#"{"min_replies":2}"#);
+    }
+}
@@ -0,0 +1,367 @@
+use chrono::{DateTime, Local, Utc};
+use reqwest::{Proxy, StatusCode, Url};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{COOKIE, HeaderName, HeaderValue, RETRY_AFTER, USER_AGENT};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{Settings, Timeouts};
+
+/// Retries after the first attempt when `retries` is not set
+const DEFAULT_RETRIES: u32 = 3;
+/// Seconds before the first retry when `retry_delay` is not set
+const DEFAULT_RETRY_DELAY: f64 = 1.0;
+/// Longest wait in seconds between attempts when `max_retry_delay` is not set
+const DEFAULT_MAX_RETRY_DELAY: f64 = 60.0;
+/// Requests per second to hosts which are not set in `rate_limits`.
+/// 4chan asks for at most one request per second to its pages and API, images on i.4cdn.org are not limited
+const DEFAULT_RATE_LIMITS: [(&str, f64); 3] = [("4chan.org", 1.0), ("4channel.org", 1.0), ("a.4cdn.org", 1.0)];
+/// Seconds to wait for a connection when `connect_timeout` is not set
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+/// Seconds to wait for a response or more data of it when `read_timeout` is not set
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+/// Timeouts of hosts which are not set in `timeouts`, iqdb.org can take a while to search without being an error
+const DEFAULT_HOST_TIMEOUTS: [(&str, Timeouts); 1] = [("iqdb.org", Timeouts { connect: None, read: Some(0), total: None })];
+
+/// User agent sent when `user_agent` is not set
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:87.0) Gecko/20100101 Firefox/87.0";
+/// Proxy of a host which connects to it directly, also when `proxy` is set
+const NO_PROXY: &str = "none";
+/// Host in `headers` matching all hosts
+pub const ALL_HOSTS: &str = "*";
+
+/// Client of each host, which keeps connections open between requests
+static CLIENTS: OnceLock<Mutex<HashMap<String, Client>>> = OnceLock::new();
+
+/// Token bucket of each host with a rate limit, shared by all requests
+static BUCKETS: OnceLock<Mutex<HashMap<String, Bucket>>> = OnceLock::new();
+/// Bytes which can be downloaded, shared by all downloads and refilled at `limit_rate`
+static BANDWIDTH: Mutex<Option<Bucket>> = Mutex::new(None);
+/// `Last-Modified` given for each url requested
+static LAST_MODIFIED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+/// Dates sent as `If-Modified-Since` the next time each url is requested
+static IF_MODIFIED_SINCE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Why a request failed, after all attempts if the failure is retried
+#[derive(Debug)]
+pub enum RequestError {
+    /// No response was received, with the error of the last attempt
+    NoResponse { url: String, attempts: u32, error: String },
+    /// The site answered with an error status
+    Status { url: String, attempts: u32, status: StatusCode },
+    /// The response could not be read
+    Invalid { url: String, error: String },
+}
+
+impl RequestError {
+    /// Status given by the site, `None` if no response was received
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::NoResponse { url, attempts: 1, error } => write!(f, "Could not get a response from {}: {}", url, error),
+            RequestError::NoResponse { url, attempts, error } => write!(f, "Could not get a response from {} after {} attempts: {}", url, attempts, error),
+            RequestError::Status { url, attempts: 1, status } => write!(f, "Response error {} received from {}", status, url),
+            RequestError::Status { url, attempts, status } => write!(f, "Response error {} received from {} after {} attempts", status, url, attempts),
+            RequestError::Invalid { url, error } => write!(f, "Could not read the response from {}: {}", url, error),
+        }
+    }
+}
+
+/// How failed requests are retried, set with `retries`, `retry_delay` and `max_retry_delay`
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each following retry
+    pub delay: Duration,
+    /// Longest delay between attempts, also for delays asked for with `Retry-After`
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> RetryPolicy {
+        RetryPolicy {
+            attempts: settings.retries.unwrap_or(DEFAULT_RETRIES).saturating_add(1),
+            delay: Duration::from_secs_f64(settings.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY).max(0.0)),
+            max_delay: Duration::from_secs_f64(settings.max_retry_delay.unwrap_or(DEFAULT_MAX_RETRY_DELAY).max(0.0)),
+        }
+    }
+
+    /// Delay before retrying after the given failed attempt, starting at 1.
+    /// The delay is doubled for each attempt, and a random part of it is left out so clients do not retry at the same time
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(self.max_delay);
+        delay / 2 + delay.mul_f64(random_fraction() / 2.0)
+    }
+
+    /// Delay before retrying after the given failed attempt, as asked for by the response if it has a `Retry-After` header
+    pub fn delay_for(&self, attempt: u32, resp: Option<&Response>) -> Duration {
+        match resp.and_then(retry_after) {
+            Some(delay) => delay.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+/// Returns true if a request which got the status may work when it is tried again
+pub fn is_retried_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Returns true if a request which failed with the error may work when it is tried again
+pub fn is_retried_error(error: &reqwest::Error) -> bool {
+    // Other errors like invalid urls fail the same way every time
+    error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
+}
+
+/// Returns the delay asked for by the `Retry-After` header of the response, which is given in seconds or as a date
+pub fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    // Dates in the past mean that the request can be retried right away
+    Some((date - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Requests which can be made to a host, refilled at the rate limit of the host
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Returns the value set for the host or one of its parent domains, the value set for the longest matching host is used
+fn for_host<'a, T: 'a>(host: &str, values: impl Iterator<Item = (&'a str, T)>) -> Option<T> {
+    values
+        .filter(|(h, _)| host == *h || host.ends_with(&format!(".{}", h)))
+        .max_by_key(|(h, _)| h.len())
+        .map(|(_, value)| value)
+}
+
+/// Returns the lowercase host of the url, `None` if the url is not valid
+fn host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url).ok()?.host_str().map(|h| h.to_lowercase())
+}
+
+/// Returns the requests per second allowed to the host, `None` if it is not limited
+fn rate_limit(host: &str) -> Option<f64> {
+    let settings = crate::config::settings();
+    let configured = for_host(host, settings.rate_limits.iter().map(|(h, rate)| (h.as_str(), *rate)));
+    let default = for_host(host, DEFAULT_RATE_LIMITS.iter().copied());
+    // Limits of 0 turn off the default limit of a host
+    configured.or(default).filter(|rate| *rate > 0.0)
+}
+
+/// Returns the timeouts of the host, taken from the settings of the host, then the default of the host and then the global settings.
+/// Timeouts of 0 are turned off
+fn timeouts(host: &str) -> Timeouts {
+    let settings = crate::config::settings();
+    let configured = for_host(host, settings.timeouts.iter().map(|(h, t)| (h.as_str(), *t))).unwrap_or_default();
+    let default = for_host(host, DEFAULT_HOST_TIMEOUTS.iter().copied()).unwrap_or_default();
+    Timeouts {
+        connect: configured.connect.or(default.connect).or(settings.connect_timeout).or(Some(DEFAULT_CONNECT_TIMEOUT)).filter(|t| *t > 0),
+        read: configured.read.or(default.read).or(settings.read_timeout).or(Some(DEFAULT_READ_TIMEOUT)).filter(|t| *t > 0),
+        total: configured.total.or(default.total).or(settings.total_timeout).filter(|t| *t > 0),
+    }
+}
+
+/// Returns the proxy of the host, from `proxies` or else `proxy`. `None` if it is connected to directly
+fn proxy(host: &str) -> Option<&'static str> {
+    let settings = crate::config::settings();
+    for_host(host, settings.proxies.iter().map(|(h, p)| (h.as_str(), p.as_str())))
+        .or(settings.proxy.as_deref())
+        .filter(|p| *p != NO_PROXY)
+}
+
+/// Returns the extra headers sent to the host, headers of a more specific host replace headers with the same name
+fn headers(host: &str) -> Vec<(&'static str, &'static str)> {
+    let settings = crate::config::settings();
+    let mut hosts: Vec<&String> = settings.headers.keys()
+        .filter(|h| *h == ALL_HOSTS || host == *h || host.ends_with(&format!(".{}", h)))
+        .collect();
+    hosts.sort_by_key(|h| if *h == ALL_HOSTS { 0 } else { h.len() });
+
+    let mut headers: Vec<(&str, &str)> = Vec::new();
+    for (name, value) in hosts.iter().flat_map(|h| settings.headers[*h].iter()) {
+        headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        headers.push((name, value));
+    }
+    headers
+}
+
+/// Checks that the proxies and headers in the settings can be used, so requests do not fail for them later
+pub fn check_settings(settings: &Settings) -> Result<(), String> {
+    for proxy in settings.proxy.iter().chain(settings.proxies.values()).filter(|p| *p != NO_PROXY) {
+        Proxy::all(proxy.as_str()).map_err(|_| format!("'{}' is not a proxy, expected a url like http://localhost:8080 or socks5://localhost:1080", proxy))?;
+    }
+    for (name, value) in settings.headers.values().flatten() {
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("'{}' is not a header name", name))?;
+        HeaderValue::from_str(value).map_err(|_| format!("'{}' is not a value of header {}", value, name))?;
+    }
+    if let Some(user_agent) = &settings.user_agent {
+        HeaderValue::from_str(user_agent).map_err(|_| format!("'{}' is not a user agent", user_agent))?;
+    }
+    for provider in settings.search_providers.iter() {
+        match Url::parse(provider) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+            _ => return Err(format!("'{}' is not a search provider, expected a url like https://iqdb.org", provider)),
+        }
+    }
+    Ok(())
+}
+
+/// Returns a GET request to the url, made with the shared client of its host so connections are reused.
+/// The client waits `connect` seconds for a connection and `read` seconds for the response and each part of it,
+/// the whole request including the download of the body is limited to `total` seconds.
+/// The request goes through the proxy of the host and has the user agent, extra headers and cookies for the url
+pub fn request(url: &str) -> RequestBuilder {
+    let settings = crate::config::settings();
+    let host = host(url).unwrap_or_default();
+    let timeouts = timeouts(&host);
+    let client = CLIENTS.get_or_init(Default::default).lock().unwrap()
+        .entry(host.clone())
+        .or_insert_with(|| {
+            let builder = Client::builder()
+                .connect_timeout(timeouts.connect.map(Duration::from_secs))
+                .timeout(timeouts.read.map(Duration::from_secs));
+            // Proxies are checked by check_settings
+            let builder = match proxy(&host) {
+                Some(proxy) => builder.proxy(Proxy::all(proxy).unwrap()),
+                None => builder,
+            };
+            builder.build().expect("Could not create HTTP client")
+        })
+        .clone();
+
+    let mut request = client.get(url)
+        .header(USER_AGENT, settings.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+    for (name, value) in headers(&host) {
+        request = request.header(name, value);
+    }
+    if let Some(cookies) = crate::cookies::header(url) {
+        request = request.header(COOKIE, cookies);
+    }
+    match timeouts.total {
+        Some(total) => request.timeout(Duration::from_secs(total)),
+        None => request,
+    }
+}
+
+/// Waits until a request to the host of the url is allowed by the rate limit of the host.
+/// Each host has a token bucket holding as many requests as are allowed per second, at least one
+pub fn wait_for_rate_limit(url: &str) {
+    let host = match host(url) {
+        Some(h) => h,
+        None => return,
+    };
+    let rate = match rate_limit(&host) {
+        Some(r) => r,
+        None => return,
+    };
+
+    let wait = {
+        let mut buckets = BUCKETS.get_or_init(Default::default).lock().unwrap();
+        let now = Instant::now();
+        let capacity = rate.max(1.0);
+        let bucket = buckets.entry(host).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        // The request is counted before waiting, so other requests wait for the following tokens
+        bucket.tokens -= 1.0;
+        Duration::from_secs_f64((-bucket.tokens / rate).max(0.0))
+    };
+    if !wait.is_zero() {
+        crate::debug_output("rate limit", &format!("Waiting {:.2}s for {}", wait.as_secs_f64(), url));
+        std::thread::sleep(wait);
+    }
+}
+
+/// Returns the bytes per second all downloads may use at the moment, `None` if downloads are not limited.
+/// The lowest limit of `limit_rate` and the quiet hours of the current time is used, limits of 0 are ignored
+pub fn limit_rate() -> Option<u64> {
+    let settings = crate::config::settings();
+    let now = Local::now().time();
+    settings.quiet_hours.iter()
+        .filter(|q| q.contains(now))
+        .filter_map(|q| q.limit_rate)
+        .chain(settings.limit_rate)
+        .filter(|rate| *rate > 0)
+        .min()
+}
+
+/// Waits until the bytes may be downloaded without exceeding the limit, which is checked each time so it changes with the quiet hours.
+/// Up to a second of the limit can be downloaded at once after downloads have been idle
+fn throttle(bytes: usize) {
+    let rate = match limit_rate() {
+        Some(r) => r as f64,
+        None => return,
+    };
+
+    let wait = {
+        let mut bandwidth = BANDWIDTH.lock().unwrap();
+        let now = Instant::now();
+        let bucket = bandwidth.get_or_insert(Bucket { tokens: rate, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+        // The bytes are counted before waiting, so other downloads wait for the following bytes
+        bucket.tokens -= bytes as f64;
+        Duration::from_secs_f64((-bucket.tokens / rate).max(0.0))
+    };
+    if !wait.is_zero() {
+        std::thread::sleep(wait);
+    }
+}
+
+/// Reader which limits how fast it is read to `limit_rate`, together with all other throttled readers
+pub struct Throttled<R: Read>(pub R);
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        throttle(read);
+        Ok(read)
+    }
+}
+
+/// Returns the `Last-Modified` date given by the site the last time the url was requested
+pub fn last_modified(url: &str) -> Option<String> {
+    LAST_MODIFIED.get_or_init(Default::default).lock().unwrap().get(url).cloned()
+}
+
+/// Records the `Last-Modified` date given by the site for the url
+pub fn set_last_modified(url: &str, date: &str) {
+    LAST_MODIFIED.get_or_init(Default::default).lock().unwrap().insert(url.to_string(), date.to_string());
+}
+
+/// Sends the date as `If-Modified-Since` the next time the url is requested, so it is answered with 304 if it has not changed.
+/// Only the next request is conditional, since later requests in the same run need the page
+pub fn set_if_modified_since(url: &str, date: &str) {
+    IF_MODIFIED_SINCE.get_or_init(Default::default).lock().unwrap().insert(url.to_string(), date.to_string());
+}
+
+/// Returns the date to send as `If-Modified-Since` for the url and removes it
+pub fn take_if_modified_since(url: &str) -> Option<String> {
+    IF_MODIFIED_SINCE.get_or_init(Default::default).lock().unwrap().remove(url)
+}
+
+/// Returns a random number from 0 up to 1, used for jitter
+fn random_fraction() -> f64 {
+    // Each RandomState is seeded differently, which is random enough to spread retries
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,197 @@
+use crate::color::Color::*;
+use filetime::{FileTime, set_file_mtime};
+use serde::Deserialize;
+use std::fs::{copy, create_dir, create_dir_all, read_dir, read_link, remove_dir_all, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::dedupe::{self, HashIndex};
+use crate::watchlist::{self, WatchedThread};
+
+/// How thread directories are laid out in the root
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// `{thread} - {subject}` directly in the root
+    Flat,
+    /// `{board}/{thread} - {subject}`
+    Nested,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Layout::Flat),
+            "nested" => Ok(Layout::Nested),
+            _ => Err(format!("'{}' is not a layout, expected flat or nested", s)),
+        }
+    }
+}
+
+/// Creates the root if it does not exist and returns its absolute path. A leading `~` is the home directory
+pub fn open_root(root: &Path) -> PathBuf {
+    let root = match (root.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => root.to_path_buf(),
+    };
+    let root = root.as_path();
+    if !root.is_dir() {
+        statusln!("Creating root directory {}", Cyan.paint(root.display().to_string()));
+        create_dir_all(root).expect("Could not create root directory, may not have write permission");
+    }
+    root.canonicalize().expect("Could not read root directory")
+}
+
+/// Returns the directory relative to the root for a thread directory with the given name
+pub fn thread_dir(layout: Layout, url: &str, name: &str) -> String {
+    match (layout, crate::api::board(url)) {
+        (Layout::Nested, Some(board)) => format!("{}/{}", board, name),
+        _ => name.to_string(),
+    }
+}
+
+/// Moves thread directories directly in the root to `{board}/` directories.
+/// The board is taken from the watch list, or from the saved posts for threads which are no longer watched
+pub fn migrate(threads: &mut [WatchedThread], dry_run: bool) {
+    let root = crate::config::root();
+    let mut index = HashIndex::load();
+    let mut moved = 0;
+
+    let mut dirs: Vec<PathBuf> = match read_dir(root) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).filter(|p| p.is_dir()).collect(),
+        Err(e) => {
+            println!("{} Could not read {}: {}", Red.paint("Error:"), root.display(), e);
+            std::process::exit(1);
+        }
+    };
+    dirs.sort();
+
+    for dir_path in dirs {
+        let name = dir_path.file_name().unwrap().to_string_lossy().to_string();
+        let thread = threads.iter_mut().find(|t| t.dir == name);
+        let url = match (&thread, crate::posts::read_posts(&dir_path)) {
+            (Some(thread), _) => thread.url.clone(),
+            (None, Some(saved)) => saved.url,
+            // Board directories and directories which are not threads
+            (None, None) => continue,
+        };
+        let new_dir = thread_dir(Layout::Nested, &url, &name);
+        if new_dir == name {
+            println!("{} Board of {} is not known, not moving it", Yellow.paint("Warning:"), name);
+            continue;
+        }
+        let new_path = root.join(&new_dir);
+        if new_path.exists() {
+            println!("{} {} already exists, not moving {}", Yellow.paint("Warning:"), new_dir, name);
+            continue;
+        }
+
+        print!("Moving {} to {} ", name, Cyan.paint(&new_dir));
+        if dry_run {
+            println!("{}", Blue.paint("(dry run)"));
+        }
+        else {
+            let moved_dir = create_dir_all(new_path.parent().unwrap()).and_then(|_| move_dir(&dir_path, &new_path));
+            match moved_dir {
+                Ok(_) => println!("{}", Green.paint("Done")),
+                Err(e) => {
+                    println!("{} {}", Red.paint("Failed:"), e);
+                    continue;
+                }
+            }
+            if let Some(thread) = thread {
+                thread.dir = new_dir;
+            }
+            index.move_dir(&dir_path, &new_path);
+        }
+        moved += 1;
+    }
+
+    println!("{} {} thread directories", if dry_run { "Would move" } else { "Moved" }, moved);
+    if !dry_run && moved > 0 {
+        watchlist::save(threads);
+        if root.join(dedupe::FILE_NAME).is_file() {
+            index.save();
+        }
+    }
+}
+
+/// Moves the directory of the watched thread at `i` to `new_dir` in the root and updates the watch list.
+/// Nothing is moved if `new_dir` already exists
+pub fn rename_thread(threads: &mut [WatchedThread], i: usize, new_dir: String) {
+    let root = crate::config::root();
+    let old_dir = threads[i].dir.clone();
+    let (old_path, new_path) = (root.join(&old_dir), root.join(&new_dir));
+
+    if new_path.exists() {
+        println!("{} {} already exists", Red.paint("Error:"), new_dir);
+        std::process::exit(1);
+    }
+    if !old_path.is_dir() {
+        println!("{} {} does not exist", Red.paint("Error:"), old_dir);
+        std::process::exit(1);
+    }
+
+    print!("Moving {} to {} ", old_dir, Cyan.paint(&new_dir));
+    if let Err(e) = create_dir_all(new_path.parent().unwrap()).and_then(|_| move_dir(&old_path, &new_path)) {
+        println!("{} {}", Red.paint("Failed:"), e);
+        std::process::exit(1);
+    }
+
+    threads[i].dir = new_dir;
+    if let Err(e) = watchlist::try_save(threads) {
+        // The directory is moved back so the watch list still points to it
+        println!("{} Could not write watch list: {}", Red.paint("Failed:"), e);
+        if let Err(e) = move_dir(&new_path, &old_path) {
+            println!("{} Could not move {} back: {}", Red.paint("Error:"), new_path.display(), e);
+        }
+        std::process::exit(1);
+    }
+    println!("{}", Green.paint("Done"));
+
+    if root.join(dedupe::FILE_NAME).is_file() {
+        let mut index = HashIndex::load();
+        index.move_dir(&old_path, &new_path);
+        index.save();
+    }
+}
+
+/// Moves a directory, copying it when it is moved to another filesystem
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            crate::debug_output("copying", &format!("{} is on another filesystem", to.display()));
+            if let Err(e) = copy_dir(from, to) {
+                // Partly copied directories are removed so the move can be retried
+                let _ = remove_dir_all(to);
+                return Err(e);
+            }
+            remove_dir_all(from)
+        },
+        result => result,
+    }
+}
+
+/// Copies a directory with all files in it, keeping modify dates and symbolic links
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    create_dir(to)?;
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        }
+        else if file_type.is_symlink() {
+            dedupe::symlink(&read_link(entry.path())?, &target)?;
+        }
+        else {
+            copy(entry.path(), &target)?;
+            set_file_mtime(&target, FileTime::from_last_modification_time(&entry.metadata()?))?;
+        }
+    }
+    Ok(())
+}
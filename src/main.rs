@@ -4,30 +4,72 @@ extern crate select;
 extern crate ansi_term;
 extern crate filetime;
 
-use reqwest::blocking::{Response, Client};
+use reqwest::blocking::Response;
+use reqwest::StatusCode;
+use reqwest::header::{IF_MODIFIED_SINCE, LAST_MODIFIED};
 use select::{document::Document, predicate::{Class, Name}};
 use tempfile::NamedTempFile;
 use core::time;
-use std::{process, sync::atomic::{AtomicBool, Ordering}, thread, time::SystemTime};
-use std::io::Write;
+use std::{process, sync::{Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::{Instant, SystemTime}};
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
-use std::fs::{File, create_dir, read_dir, read_to_string, copy};
-use ansi_term::Color::*;
+use std::fs::{create_dir, create_dir_all, remove_dir_all};
+use color::Color::*;
 use filetime::{FileTime, set_file_mtime};
+use dedupe::Deduplicator;
+use filter::Filters;
+use media::Media;
+use thread_ref::ThreadRef;
+use watchlist::WatchedThread;
+use http::RequestError;
+use report::Event;
 
+/// Prints like `print!`, to standard error when standard output is used for events with `--output`
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if crate::report::is_text() { print!($($arg)*) } else { eprint!($($arg)*) }
+    };
+}
+
+/// Prints like `println!`, to standard error when standard output is used for events with `--output`
+macro_rules! statusln {
+    ($($arg:tt)*) => {
+        if crate::report::is_text() { println!($($arg)*) } else { eprintln!($($arg)*) }
+    };
+}
+
+mod api;
 mod cli;
+mod color;
+mod config;
+mod cookies;
+mod dedupe;
+mod filter;
+mod http;
+mod library;
+mod media;
+mod naming;
+mod posts;
+mod quota;
+mod report;
+mod similar;
+mod snapshot;
+mod thread_ref;
+mod transfer;
+mod watchlist;
 
 // Mostly ideas for new features
 //TODO: Add flag to hide "could not get response" warning. Alternatively to show them in the first place
 //TODO: Add iqdb subcommand where local image specified gets posted to iqdb and a larger image is received.
 //TODO: To increase speed search for new links if an image has not been found or does not work. (Use objects which has a 'call next link' method)
 //TODO: Add progress bar, like when compiling with cargo
-//TODO: add renaming subcommand where folder name & name in threads.txt are updated
 static DEBUG: AtomicBool = AtomicBool::new(false);
 static PRINT_NUMBERED: AtomicBool = AtomicBool::new(true);
 
-const USER_AGENT: &str = "user-agent";
-const USER_AGENT_VALUE: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:87.0) Gecko/20100101 Firefox/87.0";
+/// Subfolder of the thread directory thumbnails are saved to
+const THUMBNAIL_DIR: &str = "thumbs";
+/// Site searched for images with --iqdb when `search_providers` is not set
+const DEFAULT_SEARCH_PROVIDER: &str = "https://iqdb.org";
 
 
 fn main() {
@@ -38,151 +80,590 @@ fn main() {
     if matches.is_present("debug") {
         DEBUG.store(true, Ordering::Relaxed);
     }
+    // Output format and color are validated by clap
+    let output = match matches.subcommand() {
+        ("download", Some(args)) | ("update", Some(args)) => args.value_of("output").unwrap().parse().unwrap(),
+        _ => report::Output::Text,
+    };
+    report::set_output(output);
+    let is_terminal = if report::is_text() { std::io::stdout().is_terminal() } else { std::io::stderr().is_terminal() };
+    color::set(matches.value_of("color").unwrap().parse().unwrap(), is_terminal);
+
+    // Settings from the configuration files are used when an option is not passed
+    let mut settings = config::load(matches.value_of("profile"));
+    if let Some(root) = matches.value_of("root") {
+        settings.root = Some(PathBuf::from(root));
+    }
+    // Layout is validated by clap
+    if let Some(layout) = matches.value_of("layout") {
+        settings.layout = Some(layout.parse().unwrap());
+    }
+    if let Some(template) = matches.value_of("dir-template") {
+        settings.dir_template = Some(template.to_string());
+    }
+    // Flags turn on options, and --no-... flags turn off options which are turned on in the configuration files
+    let flag = |setting: Option<bool>, on: bool, off: bool| if on { Some(true) } else if off { Some(false) } else { setting };
+    settings.portable_names = flag(settings.portable_names, matches.is_present("portable-names"), matches.is_present("no-portable-names"));
+    settings.not_numbered = flag(settings.not_numbered, matches.is_present("not-numbered"), matches.is_present("numbered"));
+    settings.update_modify_date = flag(settings.update_modify_date, matches.is_present("update-modify-date"), matches.is_present("no-update-modify-date"));
+    if let Some(retries) = matches.value_of("retries") {
+        settings.retries = match retries.parse() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                statusln!("{} '{}' is not a number of retries", Red.paint("Error:"), retries);
+                process::exit(1);
+            }
+        };
+    }
+    if let Some(jobs) = matches.value_of("jobs") {
+        settings.jobs = match jobs.parse() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                statusln!("{} '{}' is not a number of jobs", Red.paint("Error:"), jobs);
+                process::exit(1);
+            }
+        };
+    }
+    if settings.jobs == Some(0) {
+        statusln!("{} At least one job is needed to download files", Red.paint("Error:"));
+        process::exit(1);
+    }
+    if let Some(limit) = matches.value_of("limit-rate") {
+        settings.limit_rate = match filter::parse_size(limit) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                statusln!("{} {}", Red.paint("Error:"), e);
+                process::exit(1);
+            }
+        };
+    }
+    if let Some(user_agent) = matches.value_of("user-agent") {
+        settings.user_agent = Some(user_agent.to_string());
+    }
+    if let Some(proxy) = matches.value_of("proxy") {
+        settings.proxy = Some(proxy.to_string());
+    }
+    for header in matches.values_of("header").into_iter().flatten() {
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => {
+                statusln!("{} '{}' is not a header, expected 'Name: value'", Red.paint("Error:"), header);
+                process::exit(1);
+            }
+        };
+        settings.headers.entry(http::ALL_HOSTS.to_string()).or_default().insert(name.to_string(), value.to_string());
+    }
+    if let Some(providers) = matches.values_of("search-provider") {
+        settings.search_providers = providers.map(String::from).collect();
+    }
+    if let Some(cookies) = matches.value_of("cookies") {
+        settings.cookies = Some(PathBuf::from(cookies));
+    }
+    if let Err(e) = http::check_settings(&settings) {
+        statusln!("{} {}", Red.paint("Error:"), e);
+        process::exit(1);
+    }
+    if let Some(path) = &settings.cookies {
+        match cookies::load(path) {
+            Ok(count) => debug_output("cookies", &format!("{} loaded from {}", count, path.display())),
+            Err(e) => {
+                statusln!("{} {}", Red.paint("Error:"), e);
+                process::exit(1);
+            }
+        }
+    }
+    settings.root = Some(library::open_root(settings.root.as_deref().unwrap_or_else(|| Path::new("."))));
+    config::set(settings);
+    let settings = config::settings();
+    debug_output("settings", &format!("{:#?}", settings));
+
     // Disables numbered output when flag is passed
-    if matches.is_present("not-numbered") {
+    if settings.not_numbered == Some(true) {
         PRINT_NUMBERED.store(false, Ordering::Relaxed)
     }
-    if matches.is_present("update-modify-date") {
+    if settings.update_modify_date == Some(true) {
         update_modify_date = true;
     }
 
-    let mut threads = match file_to_vec() {
-        Ok(f) => f,
-        Err(_) => Vec::new(),
+    let mut threads: Vec<WatchedThread> = watchlist::load();
+    let mut dedupe: Option<Deduplicator> = match matches.value_of("dedupe").or(settings.dedupe.as_deref()).map(|p| p.parse()) {
+        Some(Ok(policy)) => Some(Deduplicator::new(policy)),
+        Some(Err(e)) => {
+            statusln!("{} {}", Red.paint("Error:"), e);
+            process::exit(1);
+        },
+        None => None,
     };
 
     match matches.subcommand() {
         ("update", Some(args)) => {
-            threads.retain(|thread| {
-                debug_output("update url", &thread.0);
-                let res = chan(&thread.0, 
-                    update_modify_date, 
-                    Some(&thread.1), 
-                    None,
-                    // iqdb is not supported with update
-                    false,
-                    false,
-                    args.is_present("print-existing-images")
-                    
-                );
-                // Removes thread from file if chan returns None, which means that the thread has been archived.
-                // Keeps element if chan returns Some
-                if res.is_none() {
-                    false
+            // Set when the disk is almost full or the library quota is reached, the remaining threads are kept for the next update
+            let mut stopped = false;
+            threads.retain_mut(|thread| {
+                if thread.paused {
+                    debug_output("paused", &thread.url);
+                    return true;
                 }
-                else {
-                    true
+                if stopped {
+                    return true;
+                }
+                debug_output("update url", &thread.url);
+                if let Some(date) = &thread.last_modified {
+                    http::set_if_modified_since(&thread.url, date);
+                }
+                report::start_thread(&thread.url);
+                let filters = thread.filters.clone().or(settings.filters.clone());
+                let options = ThreadOptions {
+                    update_modify_date,
+                    dir: Some(&thread.dir),
+                    name: None,
+                    // iqdb is not supported with update
+                    iqdb: false,
+                    keep_original: false,
+                    override_enabled: false,
+                    // Posts, snapshots and thumbnails are updated if they have been saved before
+                    save_posts: false,
+                    snapshot: false,
+                    thumbnails: false,
+                    filters: &filters,
+                    print_existing_images: args.is_present("print-existing-images"),
+                };
+                let res = chan(&thread.url, &options, dedupe.as_mut());
+                let summary = end_thread_report(&res);
+                // Removes thread from file if it could not be found, which means that the thread has been archived.
+                // Keeps threads which could not be downloaded for other reasons
+                match res {
+                    Ok(_) => {
+                        thread.last_update = Some(watchlist::now());
+                        // Failed files and files over a quota are tried again by the next update, which would be skipped if the thread has not changed
+                        if summary.files.failed == 0 && summary.files.over_quota == 0 {
+                            thread.last_modified = http::last_modified(&thread.url).or(thread.last_modified.take());
+                        }
+                        else {
+                            thread.last_modified = None;
+                        }
+                        true
+                    },
+                    Err(ThreadError::NotFound) => {
+                        statusln!("Thread {} has been archived, removing from file", &thread.url);
+                        false
+                    },
+                    Err(e @ ThreadError::OverQuota { .. }) => {
+                        statusln!("{} {}, pausing {}. Resume it with 'chan resume'", Yellow.paint("Warning:"), e, &thread.url);
+                        thread.paused = true;
+                        thread.last_modified = None;
+                        true
+                    },
+                    Err(e @ ThreadError::Stopped(_)) => {
+                        statusln!("{} {}", Red.paint("Error:"), e);
+                        stopped = true;
+                        true
+                    },
+                    Err(e) => {
+                        statusln!("{} {}", Red.paint("Error:"), e);
+                        true
+                    },
                 }
             });
-            debug_output("saving", "Updating threads.txt file");
-            vec_to_file(threads);
+            debug_output("saving", "Updating watch list");
+            watchlist::save(&threads);
+            if let Some(dedupe) = &dedupe {
+                dedupe.save();
+            }
+            process::exit(report::finish());
         },
         ("download", Some(args)) => {
-            let url: String = args.value_of("url").expect("No url provided").to_string();
+            let mut inputs: Vec<String> = Vec::new();
+            // Threads can be given as separate board and number arguments like 'g 123'
+            for input in thread_ref::join_board_args(args.values_of("url").into_iter().flatten().map(String::from).collect()) {
+                if input == "-" {
+                    inputs.append(&mut read_url_list(&input));
+                }
+                else {
+                    inputs.push(input);
+                }
+            }
+            if let Some(file) = args.value_of("input-file") {
+                inputs.append(&mut read_url_list(file));
+            }
 
-            let thread = chan(&url, 
-                update_modify_date, 
-                args.value_of("directory"), 
-                args.value_of("name"), 
-                args.is_present("iqdb"),
-                args.is_present("override"),
-                true
-            ).unwrap();
+            // All inputs are checked before downloading, so a typo does not stop a batch halfway
+            let mut urls: Vec<String> = Vec::new();
+            let mut invalid = false;
+            for input in inputs {
+                match ThreadRef::parse(&input) {
+                    // Keeps the first of each thread
+                    Ok(thread) => if !urls.contains(&thread.url()) { urls.push(thread.url()) },
+                    Err(e) => {
+                        statusln!("{} {}", Red.paint("Error:"), e);
+                        invalid = true;
+                    }
+                }
+            }
+            if invalid {
+                process::exit(1);
+            }
+            if urls.is_empty() {
+                statusln!("{} No thread links given", Red.paint("Error:"));
+                process::exit(1);
+            }
 
-            if ! args.is_present("iqdb") {
-                // Add link to threads file for 'update' subcommand if not present
-                threads.push(thread.clone());
-                threads.dedup();
+            let filters: Filters = match Filters::from_args(args) {
+                Ok(f) => f,
+                Err(e) => {
+                    statusln!("{} {}", Red.paint("Error:"), e);
+                    process::exit(1);
+                }
+            };
 
-                // Saves threads after chan() call to avoid non-working links
-                debug_output("saving", "Saving url to file");
-                vec_to_file(threads);
+            if args.value_of("directory").is_some_and(|d| d.contains('/')) {
+                statusln!("{} directory cannot contain '/' character", Red.paint("Error:"));
+                process::exit(1);
+            }
+            if args.is_present("directory") && urls.len() > 1 {
+                statusln!("{} --dir can only be used when downloading a single thread", Red.paint("Error:"));
+                process::exit(1);
             }
-        }
-        _ => println!("No Subcommands; how is this possible?"),
-    }
-}
 
-/// The procedure of grabbing information to downloading the images from the thread. Returns None if thread has been archived
-fn chan<S: AsRef<str>>(
-        url: S, 
-        update_modify_date: bool,
-        param_dir: Option<&str>,
-        param_name: Option<&str>,
-        iqdb: bool,
-        override_enabled: bool,
-        print_existing_images: bool,
-) -> Option<(String, String)> {
-    let url: String = url.as_ref().to_string();
-    let dir: String;
-    let dir_path: PathBuf;
-    let mut number: u64 = 0;
-    let mut urls: Vec<String>;
-    // Contains links to images that is to be downloaded
-    let img_links: Vec<String> = Vec::new();
+            // Failures are collected and shown in the summary, so one failing thread does not stop the others
+            for url in urls {
+                let dir: Option<String> = args.value_of("directory")
+                    .map(|d| naming::named_thread_dir(&url, d));
 
+                report::start_thread(&url);
+                // Filters from the configuration are not stored with the thread, so changing them applies to all threads
+                let thread_filters = filters.clone().or(settings.filters.clone());
+                let options = ThreadOptions {
+                    update_modify_date,
+                    dir: dir.as_deref(),
+                    name: args.value_of("name"),
+                    iqdb: args.is_present("iqdb"),
+                    keep_original: args.is_present("keep-original"),
+                    override_enabled: args.is_present("override"),
+                    save_posts: flag(settings.save_posts, args.is_present("save-posts"), args.is_present("no-save-posts")) == Some(true),
+                    snapshot: flag(settings.snapshot, args.is_present("snapshot"), args.is_present("no-snapshot")) == Some(true),
+                    thumbnails: flag(settings.thumbnails, args.is_present("thumbnails"), args.is_present("no-thumbnails")) == Some(true),
+                    filters: &thread_filters,
+                    print_existing_images: true,
+                };
+                let result = chan(&url, &options, dedupe.as_mut());
+                let summary = end_thread_report(&result);
 
-    if param_dir.is_some() {
-        dir = param_dir.unwrap().to_string();
-        // TODO: Add other non permitted characters
-        if dir.contains("/") {
-            println!("{} directory cannot contain '/' character", Red.paint("Error:"));
-            std::process::exit(1);
-        }
-    }
-    else if param_name.is_some() {
-        let thread_id = match get_name(&url, true) {
-            Ok(t) => t,
-            Err(r) => {
-                if r.is_none() {
-                    println!("{} Could not get a response from {}", Red.paint("Error:"), &url);
+                if let Some(dedupe) = &dedupe {
+                    dedupe.save();
                 }
-                else if r.as_ref().unwrap().status() == 404 {
-                    println!("{} Thread {} could not be found, site returned 404 status error", Red.paint("Error:"), &url);
+
+                match &result {
+                    Ok(thread) if ! args.is_present("iqdb") => {
+                        // Add link to watch list for 'update' subcommand, replacing the filters if it is already watched
+                        let watched = WatchedThread {
+                            filters: filters.clone(),
+                            last_update: Some(watchlist::now()),
+                            last_modified: http::last_modified(&thread.0).filter(|_| summary.files.failed == 0 && summary.files.over_quota == 0),
+                            ..WatchedThread::new(thread.0.clone(), thread.1.clone())
+                        };
+                        threads.retain(|t| t.key() != watched.key());
+                        threads.push(watched);
+
+                        // Saves threads after chan() call to avoid non-working links
+                        debug_output("saving", "Saving url to watch list");
+                        watchlist::save(&threads);
+                    },
+                    Err(ThreadError::OverQuota { dir, message }) if ! args.is_present("iqdb") => {
+                        // Threads over their quota are watched but paused, so they can be resumed when the quota is raised
+                        statusln!("{} {}, the thread is added to the watch list paused", Yellow.paint("Warning:"), message);
+                        let watched = WatchedThread {
+                            filters: filters.clone(),
+                            paused: true,
+                            last_update: Some(watchlist::now()),
+                            ..WatchedThread::new(url.clone(), dir.clone())
+                        };
+                        threads.retain(|t| t.key() != watched.key());
+                        threads.push(watched);
+                        watchlist::save(&threads);
+                    },
+                    Err(e @ ThreadError::Stopped(_)) => {
+                        statusln!("{} {}", Red.paint("Error:"), e);
+                        break;
+                    },
+                    Ok(_) => (),
+                    Err(e) => statusln!("{} {}", Red.paint("Error:"), e),
                 }
-                else {
-                    println!("{} Response error {} received from thread {}", Red.paint("Error:"), r.unwrap().status(), &url)
+            }
+            process::exit(report::finish());
+        }
+        ("dedupe", Some(args)) => {
+            let dirs: Vec<PathBuf> = match args.values_of("directories") {
+                Some(dirs) => dirs.map(PathBuf::from).collect(),
+                None => threads.iter().map(|t| config::root().join(&t.dir)).collect(),
+            };
+            // Policy is validated by clap
+            dedupe::consolidate(&dirs, args.value_of("policy").unwrap().parse().unwrap(), args.is_present("dry-run"));
+        },
+        ("list", Some(_)) => watchlist::print(&threads),
+        ("add", Some(args)) => {
+            let url: String = match ThreadRef::parse(args.value_of("url").unwrap()) {
+                Ok(thread) => thread.url(),
+                Err(e) => {
+                    println!("{} {}", Red.paint("Error:"), e);
+                    process::exit(1);
                 }
+            };
+            if threads.iter().any(|t| t.key() == WatchedThread::new(url.clone(), String::new()).key()) {
+                println!("{} {} is already watched", Yellow.paint("Warning:"), url);
                 process::exit(1);
             }
-        };
-        dir = format!("{} - {}", thread_id, param_name.unwrap());
-    }
-    else {
-        dir = match get_name(&url, false) {
-            Ok(t) => t,
-            //TODO: keep code DRY, this block is identical to the one above. Create function?
-            Err(r) => {
-                if r.is_none() {
-                    println!("{} Could not get a response from {}", Red.paint("Error:"), &url);
+            let dir: String = match args.value_of("directory") {
+                Some(dir) => naming::named_thread_dir(&url, dir),
+                None => match get_name(&url) {
+                    Ok((no, subject)) => naming::thread_dir(&url, &no, &subject),
+                    Err(_) => {
+                        println!("{} Could not get the subject of {}", Red.paint("Error:"), url);
+                        process::exit(1);
+                    }
+                },
+            };
+            println!("Watching {}, images are downloaded to {}/ with 'update'", url, Cyan.paint(&dir));
+            threads.push(WatchedThread::new(url, dir));
+            watchlist::save(&threads);
+        },
+        ("remove", Some(args)) => {
+            let i = watched_thread(&threads, args.value_of("thread").unwrap());
+            let thread = threads.remove(i);
+            watchlist::save(&threads);
+            println!("Removed {} from the watch list", thread.url);
+
+            let dir_path = config::root().join(&thread.dir);
+            if args.is_present("delete-files") && dir_path.is_dir() {
+                print!("Deleting {}/ ", thread.dir);
+                match remove_dir_all(&dir_path) {
+                    Ok(_) => println!("{}", Green.paint("Done")),
+                    Err(e) => println!("{} {}", Red.paint("Failed:"), e),
                 }
-                else if r.as_ref().unwrap().status() == 404 {
-                    println!("{} Thread {} could not be found, site returned 404 status error", Red.paint("Error:"), &url);
+            }
+        },
+        ("pause", Some(args)) | ("resume", Some(args)) => {
+            let i = watched_thread(&threads, args.value_of("thread").unwrap());
+            threads[i].paused = matches.subcommand_name() == Some("pause");
+            watchlist::save(&threads);
+            println!("{} {}", if threads[i].paused { "Paused" } else { "Resumed" }, threads[i].url);
+        },
+        ("export", Some(args)) => {
+            // Format is validated by clap
+            let exported = transfer::export(&threads, args.value_of("format").unwrap().parse().unwrap());
+            match args.value_of("output") {
+                Some(path) => {
+                    std::fs::write(path, exported).expect("Could not write exported watch list");
+                    println!("Exported {} threads to {}", threads.len(), path);
+                },
+                None => print!("{}", exported),
+            }
+        },
+        ("import", Some(args)) => {
+            let file = args.value_of("file").unwrap();
+            let contents = if file == "-" {
+                std::io::read_to_string(std::io::stdin())
+            }
+            else {
+                std::fs::read_to_string(file)
+            };
+            let contents = contents.unwrap_or_else(|e| {
+                println!("{} Could not read {}: {}", Red.paint("Error:"), file, e);
+                process::exit(1);
+            });
+            // Format is validated by clap
+            let format = args.value_of("format").map_or_else(|| transfer::detect(&contents), |f| f.parse().unwrap());
+            debug_output("import format", &format!("{:?}", format));
+            let imported = transfer::parse(&contents, format).unwrap_or_else(|e| {
+                println!("{} Could not read {}: {}", Red.paint("Error:"), file, e);
+                process::exit(1);
+            });
+
+            let total = imported.len();
+            let mut new_threads: Vec<WatchedThread> = Vec::new();
+            for mut thread in imported {
+                if threads.iter().chain(new_threads.iter()).any(|t| t.key() == thread.key()) {
+                    continue;
                 }
-                else {
-                    println!("{} Response error {} received from thread {}", Red.paint("Error:"), r.unwrap().status(), &url)
+                // Directories from other machines may not be safe here, and could point outside of the root
+                thread.dir = thread.dir.split(['/', '\\'])
+                    .map(|d| naming::sanitize(d, settings.portable_names == Some(true)))
+                    .filter(|d| !d.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                // Files of the thread may not be in the new directory yet, which a 304 for the date of the other machine would skip
+                thread.last_modified = None;
+                thread.last_update = None;
+                // Lists of urls do not have directories, which are named like new threads
+                if thread.dir.is_empty() {
+                    match get_name(&thread.url) {
+                        Ok((no, subject)) => thread.dir = naming::thread_dir(&thread.url, &no, &subject),
+                        Err(_) => {
+                            println!("{} Could not get the subject of {}, not importing it", Yellow.paint("Warning:"), thread.url);
+                            continue;
+                        }
+                    }
                 }
-                process::exit(1);
+                new_threads.push(thread);
             }
-        };
+
+            let added = transfer::merge(&mut threads, new_threads);
+            watchlist::save(&threads);
+            println!("Imported {} threads, {} were already watched or could not be imported", added, total - added);
+        },
+        ("rename", Some(args)) => {
+            let i = watched_thread(&threads, args.value_of("thread").unwrap());
+
+            let new_dir: String = if args.is_present("from-subject") {
+                let url = threads[i].url.clone();
+                match get_name(&url) {
+                    Ok((no, subject)) => naming::thread_dir(&url, &no, &subject),
+                    Err(_) => {
+                        println!("{} Could not get the subject of {}", Red.paint("Error:"), url);
+                        process::exit(1);
+                    }
+                }
+            }
+            else {
+                let portable = settings.portable_names == Some(true);
+                let new_name = args.value_of("new-name").unwrap();
+                let new_dir: Vec<String> = new_name.split('/')
+                    .map(|n| naming::sanitize(n, portable))
+                    .filter(|n| !n.is_empty())
+                    .collect();
+                // A name without a directory keeps the thread in the same directory, like the board directory
+                match (new_dir.len(), threads[i].dir.rsplit_once('/')) {
+                    (0, _) => {
+                        println!("{} '{}' is not a valid directory name", Red.paint("Error:"), new_name);
+                        process::exit(1);
+                    },
+                    (1, Some((parent, _))) => format!("{}/{}", parent, new_dir[0]),
+                    _ => new_dir.join("/"),
+                }
+            };
+            library::rename_thread(&mut threads, i, new_dir);
+        },
+        ("migrate", Some(args)) => {
+            library::migrate(&mut threads, args.is_present("dry-run"));
+        },
+        ("similar", Some(args)) => {
+            let dirs: Vec<PathBuf> = match args.values_of("directories") {
+                Some(dirs) => dirs.map(PathBuf::from).collect(),
+                None => threads.iter().map(|t| config::root().join(&t.dir)).collect(),
+            };
+            let max_distance: u32 = match args.value_of("distance").unwrap().parse() {
+                Ok(d) => d,
+                Err(_) => {
+                    println!("{} distance must be a number of bits", Red.paint("Error:"));
+                    process::exit(1);
+                }
+            };
+            // Algorithm is validated by clap
+            similar::find_similar(&dirs,
+                args.value_of("algorithm").unwrap().parse().unwrap(),
+                max_distance,
+                args.is_present("keep-best"),
+                args.is_present("dry-run"));
+        },
+        _ => println!("No Subcommands; how is this possible?"),
+    }
+}
+
+/// Reason a thread could not be downloaded
+#[derive(Debug)]
+enum ThreadError {
+    /// The site returned 404, which means that the thread has been archived or deleted
+    NotFound,
+    /// The thread could not be read for another reason, with a message saying why
+    Failed(String),
+    /// The quota of the thread was reached with the `stop-watching` policy, with the directory of the thread
+    OverQuota { dir: String, message: String },
+    /// Downloads were stopped since the disk is almost full or the library quota was reached, no other thread is downloaded
+    Stopped(String),
+}
+
+impl ThreadError {
+    /// Error for a request to a thread which failed
+    fn from_request(e: RequestError) -> ThreadError {
+        match e.status() {
+            Some(status) if status == 404 => ThreadError::NotFound,
+            _ => ThreadError::Failed(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ThreadError::NotFound => write!(f, "Thread could not be found, site returned 404 status error"),
+            ThreadError::Failed(message) => write!(f, "{}", message),
+            ThreadError::OverQuota { message, .. } => write!(f, "{}", message),
+            ThreadError::Stopped(message) => write!(f, "Stopped downloading: {}", message),
+        }
+    }
+}
+
+/// Options for downloading a thread with `chan`, given by the arguments of `download` or by the watched thread with `update`
+struct ThreadOptions<'a> {
+    update_modify_date: bool,
+    /// Directory of the thread, named after the thread when not given
+    dir: Option<&'a str>,
+    /// Name replacing the subject in the directory name
+    name: Option<&'a str>,
+    iqdb: bool,
+    keep_original: bool,
+    override_enabled: bool,
+    save_posts: bool,
+    snapshot: bool,
+    thumbnails: bool,
+    filters: &'a Filters,
+    print_existing_images: bool,
+}
+
+/// The procedure of grabbing information to downloading the images from the thread.
+/// Returns the url and directory of the thread, `ThreadError::NotFound` if thread has been archived
+fn chan<S: AsRef<str>>(url: S, options: &ThreadOptions, dedupe: Option<&mut Deduplicator>) -> Result<(String, String), ThreadError> {
+    let ThreadOptions { update_modify_date, iqdb, keep_original, override_enabled, save_posts, snapshot, thumbnails, filters, .. } = *options;
+    let url: String = url.as_ref().to_string();
+    let dir: String;
+    let dir_path: PathBuf;
+    let mut urls: Vec<String>;
+    // Links to the hi-res images on the thread, used to keep the originals with --keep-original
+    let mut originals: Vec<String> = Vec::new();
+    // Links to the thumbnails on the thread, only given by archive sites
+    let mut thumbnail_links: Vec<String> = Vec::new();
+
+    if let Some(param_dir) = options.dir {
+        dir = param_dir.to_string();
+    }
+    else {
+        let (thread_id, subject) = get_name(&url).map_err(ThreadError::from_request)?;
+        // The name given with --name replaces the subject
+        dir = naming::thread_dir(&url, &thread_id, options.name.unwrap_or(&subject));
     }
-    dir_path = Path::new(".").join(&dir);
+    dir_path = config::root().join(&dir);
     
-    println!("Downloading images to {}/", Cyan.paint(&dir));
+    statusln!("Downloading images to {}/", Cyan.paint(&dir));
 
     // dumps thumbnails image links on site to 'urls' to use with iqdb
     urls = match get_links(&url) { 
         Ok(links) => {
+            if keep_original {
+                originals = links.iter()
+                    .filter(|n| is_image_link(n) && !n.contains("/thumb/"))
+                    .map(|n| if ! n.starts_with("http") { n.replace("//", "https://") } else { n.to_string() })
+                    .collect();
+            }
+            if thumbnails {
+                thumbnail_links = links.iter()
+                    .filter(|n| is_image_link(n) && n.contains("/thumb/"))
+                    .map(|n| if ! n.starts_with("http") { n.replace("//", "https://") } else { n.to_string() })
+                    .collect();
+            }
             if iqdb {
                 links.into_iter()
                 // Grab only thumbnail images
-                .filter(|n| (
-                        n.ends_with(".jpg") || 
-                        n.ends_with(".gif") || 
-                        n.ends_with(".png") || 
-                        n.ends_with(".jpeg") || 
-                        n.ends_with(".webm")
-                    ) &&
+                .filter(|n| is_image_link(n) &&
                     !n.contains("url=") &&
                     n.contains("/thumb/"))
                     // Split at http to separate the two links and get the second one
@@ -195,13 +676,7 @@ fn chan<S: AsRef<str>>(
             }
             else {
                 links.into_iter()
-                .filter(|n| (
-                        n.ends_with(".jpg") || 
-                        n.ends_with(".gif") || 
-                        n.ends_with(".png") || 
-                        n.ends_with(".jpeg") || 
-                        n.ends_with(".webm") 
-                    ) &&
+                .filter(|n| is_image_link(n) &&
                     !n.contains("url=") &&
                     // Filter away all thumbnail images and only keep the hi-res ones
                     !n.contains("/thumb/"))
@@ -215,12 +690,11 @@ fn chan<S: AsRef<str>>(
                     .collect()
             }
         },
-        Err(r) => {
-            if r.unwrap().status() == 404 {
-                println!("Thread {} has been archived, removing from file", &url);
-            }
-            return None
-        }
+        Err(e) if e.status() == Some(StatusCode::NOT_MODIFIED) => {
+            statusln!("No new posts since the last update");
+            return Ok((url, dir));
+        },
+        Err(e) => return Err(ThreadError::from_request(e)),
     };
 
     urls.dedup();
@@ -229,59 +703,316 @@ fn chan<S: AsRef<str>>(
 
     // Create directory if it does not exist
     if ! dir_path.is_dir() {
-        create_dir(&dir).expect("Could not create directory, may not have write permission");
+        create_dir_all(&dir_path).expect("Could not create directory, may not have write permission");
+    }
+    quota::start_thread(&dir_path);
+
+    // Posts and snapshots are saved when they have been saved before as well, which keeps them updated with 'update'
+    let save_posts = save_posts || dir_path.join(posts::FILE_NAME).is_file();
+    let save_snapshot = snapshot || dir_path.join(snapshot::FILE_NAME).is_file();
+    // MD5 hashes given by the API are used to find duplicates before they are downloaded
+    let dedupe_metadata = dedupe.is_some() && api::board_and_thread(&url).is_some();
+    // Sizes given by the API are needed to keep to the quotas
+    let quota_metadata = (config::settings().thread_quota.is_some() || config::settings().library_quota.is_some()) && api::board_and_thread(&url).is_some();
+    let api_thread = if save_posts || save_snapshot || filters.needs_metadata() || dedupe_metadata || quota_metadata { get_api_thread(&url) } else { None };
+
+    // Filters on posts would not let any file through without the posts
+    if filters.filters_posts() && api_thread.is_none() {
+        return Err(ThreadError::Failed("Filters on posts need the posts from the 4chan API, which could not be read".to_string()));
+    }
+
+    // Filters on metadata pass if the file is not found in the thread from the API
+    let matches = filters.matcher(api_thread.as_ref().map(|t| &t.2));
+    urls.retain(|n| matches(n));
+
+    if let (true, Some((board, no, thread))) = (save_posts, &api_thread) {
+        status!("Saving posts to {}/{} ", &dir, posts::FILE_NAME);
+        posts::save_posts(&dir_path, &url, board, *no, thread);
+        statusln!("{}", Green.paint("Done"));
+    }
+
+    // Thumbnails are saved when they have been saved before as well, which keeps them updated with 'update'
+    let thumbs_dir: String = format!("{}/{}", dir, THUMBNAIL_DIR);
+    let thumbs_dir_path: PathBuf = dir_path.join(THUMBNAIL_DIR);
+    let thumbnails = ! iqdb && ( thumbnails || thumbs_dir_path.is_dir() );
+    if thumbnails && ! thumbs_dir_path.is_dir() {
+        create_dir(&thumbs_dir_path).expect("Could not create directory, may not have write permission");
+    }
+
+    // Images found on iqdb are saved to a subfolder when the originals are kept
+    let iqdb_dir: String = format!("{}/iqdb", dir);
+    let iqdb_dir_path: PathBuf = dir_path.join("iqdb");
+    if keep_original && ! iqdb_dir_path.is_dir() {
+        create_dir(&iqdb_dir_path).expect("Could not create directory, may not have write permission");
     }
 
-    for img in urls.iter() {
+    // Downloads a file with its original and thumbnail, an error stops all downloads of the thread
+    let dedupe = Mutex::new(dedupe);
+    let download_file = |number: u64, img: &String| -> Result<(), ThreadError> {
         // Path for new file
-        let file_path: Option<PathBuf>;
+        let mut file_path: Option<PathBuf>;
+        // Path for the original image when --keep-original is passed
+        let mut original_path: Option<PathBuf> = None;
 
-        number += 1;
-        
-        // Downloads file
-        file_path = download(
-            &dir_path,
-            &dir, 
-            &img, 
-            img_links.clone(), 
-            override_enabled,
-            iqdb,
-            print_existing_images,
-            number
-        );
-
-        if update_modify_date && file_path.is_some() {
-            let file_path = file_path.unwrap();
-            // Timestamp to assign to file
-            // By using the number variable and checked_add() method ensures that all files are at least 1 second apart to ensure correct order in file-managers
-            let new_timestamp: FileTime = FileTime::from_system_time(SystemTime::now().checked_add(time::Duration::from_secs(number)).unwrap());
-            debug_output("file path", file_path.to_str().unwrap());
-            debug_output("new_timestamp", new_timestamp.to_string().as_str());
-            set_file_mtime(file_path, new_timestamp).expect("Could not update modified date");
+        let new_path: PathBuf = dir_path.join(img.rsplit('/').next().unwrap());
+        let post: Option<&api::Post> = api_thread.as_ref().and_then(|t| t.2.post_with_file(img));
+
+        // Free space and quotas are checked before each file which is not downloaded yet, with the size from the API if it is known
+        if ! new_path.is_file() || override_enabled {
+            match (quota::check(&dir_path, post.and_then(|p| p.fsize)), config::settings().quota_policy.unwrap_or_default()) {
+                (Ok(()), _) => (),
+                (Err(quota::Limit::DiskSpace(message)), _) | (Err(quota::Limit::Library(message)), quota::Policy::StopWatching) => {
+                    return Err(ThreadError::Stopped(message));
+                },
+                (Err(quota::Limit::Thread(message)), quota::Policy::StopWatching) => {
+                    return Err(ThreadError::OverQuota { dir: dir.clone(), message });
+                },
+                (Err(quota::Limit::Thread(message)), quota::Policy::Skip) | (Err(quota::Limit::Library(message)), quota::Policy::Skip) => {
+                    statusln!("{}{} Skipping {}: {}", numbered(number), Yellow.paint("Warning:"), new_path.file_name().unwrap().to_string_lossy(), message);
+                    report::emit(Event::OverQuota { url: img.clone(), path: new_path, reason: message });
+                    return Ok(());
+                },
+            }
+        }
+
+        if keep_original {
+            match original_link(img, &originals) {
+                Some(original) => {
+                    original_path = download(&dir_path, &dir, original, false, number, None, options);
+                },
+                None => statusln!("{} Could not find the original image of {}", Yellow.paint("Warning:"), img),
+            }
         }
         
+        // Files downloaded to another directory before are linked instead of downloaded again
+        let md5: Option<String> = post.and_then(|p| p.md5.clone());
+        let known_duplicate: Option<PathBuf> = match (dedupe.lock().unwrap().as_deref_mut(), md5) {
+            (Some(_), _) if iqdb || override_enabled || new_path.is_file() => None,
+            (Some(dedupe), Some(md5)) => dedupe.link_known(&md5, &new_path),
+            // Files without a hash from the API are known when they were skipped before
+            (Some(dedupe), None) => dedupe.skipped(&new_path),
+            (None, _) => None,
+        };
+
+        if let Some(known_duplicate) = known_duplicate {
+            statusln!("{}{} {} {}", numbered(number),
+                new_path.file_name().unwrap().to_string_lossy(),
+                Blue.paint("already downloaded to"),
+                known_duplicate.display());
+            report::emit(Event::Queued { url: img.clone(), path: new_path.clone() });
+            report::emit(Event::Exists {
+                url: img.clone(),
+                bytes: std::fs::metadata(&known_duplicate).map_or(0, |m| m.len()),
+                path: new_path.clone(),
+            });
+            file_path = if new_path.is_file() { Some(new_path) } else { None };
+        }
+        else {
+            // Downloads file
+            file_path = download(
+                if keep_original { &iqdb_dir_path } else { &dir_path },
+                if keep_original { &iqdb_dir } else { &dir },
+                img,
+                iqdb,
+                number,
+                original_path.as_deref(),
+                options
+            );
+        }
+
+        // Downloaded files identical to a file downloaded before are replaced
+        if let (Some(dedupe), Some(path)) = (dedupe.lock().unwrap().as_deref_mut(), &file_path) {
+            if let Some(original) = dedupe.add(path) {
+                statusln!("{}{} {} {}", numbered(number),
+                    path.file_name().unwrap().to_string_lossy(),
+                    Blue.paint("is identical to"),
+                    original.display());
+                if ! path.is_file() {
+                    file_path = None;
+                }
+            }
+        }
+
+        if thumbnails {
+            match thumbnail_link(img, &thumbnail_links) {
+                Some(thumbnail) => {
+                    download(&thumbs_dir_path, &thumbs_dir, &thumbnail, false, number, None, options);
+                },
+                None => debug_output("no thumbnail", img),
+            }
+        }
+
+        if update_modify_date {
+            for path in [original_path, file_path].iter().flatten() {
+                set_modify_date(path, number);
+            }
+        }
+        Ok(())
+    };
+
+    // Files are downloaded by the jobs at the same time, each taking the next file until all are done or one stops the downloads
+    let jobs = config::settings().jobs.unwrap_or(1);
+    let next = AtomicUsize::new(0);
+    let stopped: Mutex<Option<ThreadError>> = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(urls.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= urls.len() || stopped.lock().unwrap().is_some() {
+                    break;
+                }
+                if let Err(e) = download_file(i as u64 + 1, &urls[i]) {
+                    stopped.lock().unwrap().get_or_insert(e);
+                }
+            });
+        }
+    });
+    if let Some(e) = stopped.into_inner().unwrap() {
+        return Err(e);
+    }
+
+    // The snapshot is written after the images are downloaded since it links to them
+    if let (true, Some((board, no, thread))) = (save_snapshot, &api_thread) {
+        if snapshot || snapshot::is_outdated(&dir_path, &url, board, *no, thread) {
+            status!("Saving snapshot to {}/{} ", &dir, snapshot::FILE_NAME);
+            snapshot::save_snapshot(&dir_path, &url, board, *no, thread);
+            statusln!("{}", Green.paint("Done"));
+        }
+    }
+
+    return Ok((url, dir));
+}
+
+/// Writes the summary event of the thread downloaded by `chan` and returns the summary
+fn end_thread_report(result: &Result<(String, String), ThreadError>) -> report::ThreadSummary {
+    match result {
+        Ok((_, dir)) => report::end_thread(Some(dir), "done", None),
+        Err(ThreadError::OverQuota { dir, message }) => report::end_thread(Some(dir), "over-quota", Some(message.clone())),
+        Err(e @ ThreadError::Stopped(_)) => report::end_thread(None, "stopped", Some(e.to_string())),
+        Err(e @ ThreadError::NotFound) => report::end_thread(None, "not-found", Some(e.to_string())),
+        Err(e) => report::end_thread(None, "failed", Some(e.to_string())),
+    }
+}
+
+/// Returns the thread links in a file with one link per line, `-` reads from standard input.
+/// Empty lines and lines starting with `#` are left out
+fn read_url_list(path: &str) -> Vec<String> {
+    let contents = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    }
+    else {
+        std::fs::read_to_string(path)
+    };
+    match contents {
+        Ok(contents) => contents.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.to_string())
+            .collect(),
+        Err(e) => {
+            statusln!("{} Could not read {}: {}", Red.paint("Error:"), path, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns the index of the watched thread with the given url, thread number or directory.
+/// Exits if the thread is not watched
+fn watched_thread(threads: &[WatchedThread], thread: &str) -> usize {
+    match watchlist::position(threads, thread) {
+        Some(i) => i,
+        None => {
+            statusln!("{} {} is not in the watch list", Red.paint("Error:"), thread);
+            process::exit(1);
+        }
+    }
+}
+
+/// Sets the modify date of the file to the current time offset by `number` seconds
+fn set_modify_date(file_path: &Path, number: u64) {
+    // Timestamp to assign to file
+    // By using the number variable and checked_add() method ensures that all files are at least 1 second apart to ensure correct order in file-managers
+    let new_timestamp: FileTime = FileTime::from_system_time(SystemTime::now().checked_add(time::Duration::from_secs(number)).unwrap());
+    debug_output("file path", file_path.to_str().unwrap());
+    debug_output("new_timestamp", new_timestamp.to_string().as_str());
+    set_file_mtime(file_path, new_timestamp).expect("Could not update modified date");
+}
+
+/// Returns true if the link points to a file with a supported extension
+fn is_image_link(link: &str) -> bool {
+    media::EXTENSIONS.iter().any(|ext| link.ends_with(ext))
+}
+
+/// Returns the link in `originals` to the hi-res image of the given thumbnail link
+fn original_link<'a>(thumbnail: &str, originals: &'a [String]) -> Option<&'a String> {
+    let thumbnail = Media::from_link(thumbnail)?;
+
+    originals.iter().find(|n| match Media::from_link(n) {
+        Some(original) => !original.thumbnail && original.tim == thumbnail.tim,
+        None => false,
+    })
+}
+
+/// Returns the board, thread number and thread from the 4chan JSON API.
+/// `None` if the thread could not be retrieved, a warning is printed
+fn get_api_thread(url: &str) -> Option<(String, u64, api::Thread)> {
+    let (board, no) = match api::board_and_thread(url) {
+        Some(t) => t,
+        None => {
+            statusln!("{} Posts can only be read from 4chan threads", Yellow.paint("Warning:"));
+            return None;
+        }
+    };
+
+    match api::get_thread(&board, no) {
+        Ok(thread) => Some((board, no, thread)),
+        Err(_) => {
+            statusln!("{} Could not get posts from {}", Yellow.paint("Warning:"), url);
+            None
+        }
+    }
+}
+
+/// Returns the link to the thumbnail of the given image link.
+/// Archive sites link to their thumbnails, while 4chan serves thumbnails next to the images
+fn thumbnail_link(img: &str, thumbnail_links: &[String]) -> Option<String> {
+    let media = Media::from_link(img)?;
+
+    let thumbnail = thumbnail_links.iter().find(|n| match Media::from_link(n) {
+        Some(thumbnail) => thumbnail.thumbnail && thumbnail.tim == media.tim,
+        None => false,
+    });
+    match thumbnail {
+        Some(thumbnail) => Some(thumbnail.to_string()),
+        None => Some(format!("{}/{}", img.rsplit_once('/')?.0, media.thumbnail_name())),
     }
+}
 
-    return Some((url, dir));
+/// Returns the number of a file printed before its messages, nothing with --not-numbered
+fn numbered(number: u64) -> String {
+    if PRINT_NUMBERED.load(Ordering::Relaxed) { format!("[{}] ", Blue.paint(number.to_string())) } else { String::new() }
 }
 
 fn debug_output(title: &str, message: &str) {
     if DEBUG.load(Ordering::Relaxed) {
-        println!("[{}] {} &", Purple.paint(title), message);
+        statusln!("[{}] {} &", Purple.paint(title), message);
     }
 }
 
-/// Downloads file and returns file path of the downloaded file
+/// Downloads file and returns file path of the downloaded file.
+/// `iqdb` searches iqdb for the image, which is only done for the images of the thread and not for originals and thumbnails
 fn download<P: AsRef<Path>, S: AsRef<str>>(
             dir_path: P,
             dir: S,
             img: S,
-            mut img_links: Vec<String>,
-            override_enabled: bool,
             iqdb: bool,
-            print_existing_images: bool,
             number: u64,
+            original: Option<&Path>,
+            options: &ThreadOptions,
     ) -> Option<PathBuf> {
+    let ThreadOptions { override_enabled, print_existing_images, .. } = *options;
+    // Links the image is downloaded from, the first which works is used
+    let mut img_links: Vec<String> = Vec::new();
 
     // true if iqdb does not find image
     let mut iqdb_not_found: bool = false;
@@ -291,6 +1022,8 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
     let mut iqdb_no_image_link_found: bool = false;
     // Link to iqdb image search for current image
     let mut iqdb_link: String = String::new();
+    // Source page and similarity given by iqdb for each link in img_links
+    let mut img_sources: Vec<(String, Option<String>)> = Vec::new();
     // Name for image
     let mut name: String;
     // Path for new file
@@ -301,27 +1034,29 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
     // Create name for image
     name = img.as_ref().split("/").filter(|&s| !s.is_empty()).last().unwrap().to_string();
     file_path = dir_path.as_ref().join(&name);
+    let started = Instant::now();
+    report::emit(Event::Queued { url: img.as_ref().to_string(), path: file_path.clone() });
 
     //TODO: (Should this be moved to chan() instead?)
     // This block is used with --iqdb flag and gathers all image links from all links that were scraped from the image search 
     if iqdb && ( !file_path.is_file() || override_enabled ) {
-        // Get name without extension or 's' for thumbnails
-        name = name.replace("s", "");
-        file_name = name.split(".").collect::<Vec<_>>()[0].to_string();
+        // Get name of the original image and the name without extension from the thumbnail name
+        file_name = match Media::from_link(&name) {
+            Some(media) => {
+                name = media.original().file_name();
+                media.stem()
+            },
+            None => Path::new(&name).file_stem().unwrap().to_string_lossy().to_string(),
+        };
         file_path = dir_path.as_ref().join(name.as_str());
 
         // Check if a file with the same name exists (ignores file extension)
-        let files = read_dir(&dir_path).expect("Could not read directory");
-        let mut exists = false;
-        for file in files {
-            let file = file.unwrap();
-            if file.path().to_str().unwrap().contains(&file_name) && ! override_enabled {
-                    exists = true;
-                    // Updates name with the correct extension
-                    name = file.path().file_name().unwrap().to_str().unwrap().to_string();
-                    file_path = file.path();
-                    break;
-            }
+        let existing: Option<PathBuf> = if override_enabled { None } else { media::find_with_stem(dir_path.as_ref(), &file_name) };
+        let exists = existing.is_some();
+        if let Some(existing) = existing {
+            // Updates name with the correct extension
+            name = existing.file_name().unwrap().to_string_lossy().to_string();
+            file_path = existing;
         }
         
         if exists {
@@ -332,50 +1067,63 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
 
             debug_output("img", &img.as_ref().clone());
 
-            // Create link to iqdb image search for current image (used if no image is found)
-            iqdb_link = format!("https://iqdb.org/?url={}", img.as_ref());
-            debug_output("iqdb_link", &iqdb_link);
-            
-            //BUG: Must handle when get_links returns None
-            // Lists all links on site and removes non useful links
-            let mut iqdb_urls: Vec<String> = get_links( &iqdb_link).unwrap()
-                            // Get all links before the '#' link (since all after are irrelevant)
-                            .split(|n| n == &"#".to_string())
-                            .collect::<Vec<_>>()[0].to_vec()
-                            // Format links
-                            .into_iter()
-                            // Remove first element ('/' link)
-                            .filter(|n| n != "/")
-                            // Conditioned because sometimes links already starts with "https://"
-                            .map(|n| if n.starts_with("//") { n.replace("//", "https://") } else { n } )
-                            .collect();
-            
-            // That site being the first link found means that the "No relevant matches" message is displayed
-            if ! iqdb_urls.is_empty() && iqdb_urls[0].contains("saucenao.com/search.php") {
-                iqdb_urls = Vec::new();
+            // Lists all matches together with their similarity. Providers are searched in order until one finds matches,
+            // the link to the search of the first provider is shown if none does
+            let settings = config::settings();
+            let providers: Vec<&str> = if settings.search_providers.is_empty() {
+                vec![DEFAULT_SEARCH_PROVIDER]
+            }
+            else {
+                settings.search_providers.iter().map(|p| p.trim_end_matches('/')).collect()
+            };
+            let mut iqdb_urls: Vec<(String, Option<String>)> = Vec::new();
+            // Why the last search failed, the file fails like a failed download if no provider could be searched
+            let mut search_error: Option<String> = None;
+            let mut searched = false;
+            for provider in providers {
+                let link = format!("{}/?url={}", provider, img.as_ref());
+                debug_output("iqdb_link", &link);
+                if iqdb_link.is_empty() {
+                    iqdb_link = link.clone();
+                }
+                match get_iqdb_matches(&link) {
+                    // That site being the first link found means that the "No relevant matches" message is displayed
+                    Ok(urls) if urls.first().is_some_and(|u| u.0.contains("saucenao.com/search.php")) => searched = true,
+                    Ok(urls) => {
+                        searched = true;
+                        if ! urls.is_empty() {
+                            iqdb_urls = urls;
+                            break;
+                        }
+                    },
+                    Err(e) => search_error = Some(format!("Could not search {}: {}", provider, e)),
+                }
+            }
+            if let (false, Some(error)) = (searched, search_error) {
+                statusln!("{}{} {}", numbered(number), Red.paint("Failed:"), error);
+                report::emit(Event::Failed {
+                    url: img.as_ref().to_string(),
+                    path: None,
+                    error,
+                    duration: started.elapsed().as_secs_f64(),
+                });
+                return None;
             }
 
             debug_output("urls from iqdb", &format!("{:#?}", iqdb_urls));
 
-            img_links = Vec::new();
             // Use all source links to find images and take all image links found
-            for url in iqdb_urls.iter() {
+            for (url, similarity) in iqdb_urls.iter() {
                 debug_output("loop url", url);
                 // Create array of image links found at url given by iqdb
                 let mut new_imgs = match get_links(url) {
                     Ok(links) => {
                         links.into_iter()
-                            .filter(|n| (
-                                n.ends_with(".jpg") || 
-                                n.ends_with(".gif") || 
-                                n.ends_with(".png") || 
-                                n.ends_with(".jpeg") || 
-                                n.ends_with(".webm") ) &&
-                                !n.contains("url="))
+                            .filter(|n| is_image_link(n) && !n.contains("url="))
                             .collect::<Vec<_>>()
                     },
-                    Err(r) => {
-                        debug_output("Response error", format!("{} returned {:?}", url, r).as_str());
+                    Err(e) => {
+                        debug_output("Response error", &e.to_string());
                         Vec::new()
                     }
                 };
@@ -383,6 +1131,7 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
 
                 // In case of error when new_imgs is empty
                 if ! new_imgs.is_empty() {
+                    img_sources.extend(new_imgs.iter().map(|_| (url.clone(), similarity.clone())));
                     img_links.append(&mut new_imgs);
                 }
             }
@@ -404,10 +1153,7 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
 
     if ! override_enabled && ( file_path.is_file() || iqdb_file_exists ) {
         if print_existing_images {
-            if PRINT_NUMBERED.load(Ordering::Relaxed) {
-                print!("[{}] ", Blue.paint(number.to_string()));
-            }
-            println!("{} {} in {}", 
+            statusln!("{}{} {} in {}", numbered(number), 
                 name.as_str(), 
                 Blue.paint("already exists"), 
                 dir.as_ref());
@@ -415,172 +1161,283 @@ fn download<P: AsRef<Path>, S: AsRef<str>>(
         else {
             debug_output("exists", name.as_str());
         }
+        report::emit(Event::Exists {
+            url: img.as_ref().to_string(),
+            bytes: std::fs::metadata(&file_path).map_or(0, |m| m.len()),
+            path: file_path.clone(),
+        });
     }
     else if iqdb_not_found {
-        if PRINT_NUMBERED.load(Ordering::Relaxed) {
-            print!("[{}] ", Blue.paint(number.to_string()));
-        }
-        println!("{} on iqdb.org\n\t{}", 
+        statusln!("{}{} on iqdb.org\n\t{}", numbered(number), 
         Red.paint("Image not found"),
         &iqdb_link);
+        report::emit(Event::IqdbNotFound { url: img.as_ref().to_string(), iqdb: iqdb_link });
         return None;
     }
     else if iqdb_no_image_link_found {
-        if PRINT_NUMBERED.load(Ordering::Relaxed) {
-            print!("[{}] ", Blue.paint(number.to_string()));
-        }
-        println!("Image found on iqdb.org but {}\n\t{}", 
+        statusln!("{}Image found on iqdb.org but {}\n\t{}", numbered(number), 
                 Yellow.paint("can not be downloaded automatically"), 
                 &iqdb_link);
+        report::emit(Event::Failed {
+            url: img.as_ref().to_string(),
+            path: None,
+            error: format!("Image found on iqdb.org but no image link could be found, see {}", iqdb_link),
+            duration: started.elapsed().as_secs_f64(),
+        });
         return None;
     }
     else {
-        if PRINT_NUMBERED.load(Ordering::Relaxed) {
-            print!("[{}] ", Blue.paint(number.to_string()));
-        }
-        print!("Downloading {} to {} ", name.as_str(), dir.as_ref());
-
-        if DEBUG.load(Ordering::Relaxed) {
-            println!("");
+        // With parallel jobs the line is printed when the file is done, so lines of other files are not printed into it
+        let mut line = format!("{}Downloading {} to {} ", numbered(number), name.as_str(), dir.as_ref());
+        if config::settings().jobs.unwrap_or(1) == 1 {
+            status!("{}", line);
+            line.clear();
+            if DEBUG.load(Ordering::Relaxed) {
+                statusln!("");
+            }
         }
         
+        // Index of the link in img_links which the image was downloaded from
+        let mut downloaded: Option<usize> = None;
+        // Why the last link did not work, reported if no link works
+        let mut error: String = "No links to download from".to_string();
+        let mut size: u64 = 0;
+
         // Iterate over found image urls until a with data is produced
         // BUG: Download from chan.sankakucomplex.com
         // TODO: Give error if no link works, check if break is called in for loop!
-        for url in img_links.iter() {
+        for (i, url) in img_links.iter().enumerate() {
             let extension = url.split(".").last().expect("No extension found");
             debug_output("extension", extension);
             file_path.set_extension(extension);
 
             debug_output("Trying", url.as_str());
 
-            let mut resp: Response = match get_response(&url) {
-                Some(r) => r,
-                None => {
-                    println!("Could not get a response from {}, continuing", &url);
+            let mut resp: Response = match get_response(url) {
+                Ok(r) => r,
+                Err(e) => {
+                    debug_output("not downloaded", &e.to_string());
+                    error = e.to_string();
                     continue
                 },
             };
 
             debug_output("name", &file_path.as_os_str().to_str().unwrap());
 
-            let tmpfile_named: NamedTempFile = tempfile::NamedTempFile::new().unwrap();
-            // let mut tmpfile: File = tmpfile_named.reopen().unwrap();
-            let mut tmpfile: &File = tmpfile_named.as_file();
-            debug_output("tmp_file", &format!("{:?}", &tmpfile));
-            
+            // The file is downloaded next to where it is saved, so it is on the same disk as checked by the quotas.
+            // It is removed when dropped, so a failed download never leaves a partial file with the name of the image
+            let mut tmpfile: NamedTempFile = match NamedTempFile::new_in(dir_path.as_ref()) {
+                Ok(f) => f,
+                Err(e) => {
+                    error = format!("Could not create a temporary file in {}: {}", dir.as_ref(), e);
+                    continue;
+                }
+            };
+            debug_output("tmp_file", &format!("{:?}", tmpfile.path()));
+
             // let mut file: File = File::create(&file_path.as_os_str()).expect("Could not create file");
             // std::io::copy(&mut resp, &mut file).expect("Could not download image to file");
-            std::io::copy(&mut resp, &mut tmpfile).expect("Could not download image to file");
-            copy(tmpfile_named.path(), &file_path).expect("Could not copy file, aborting");
-            tmpfile_named.close().expect("Could not delete temporary file");
+            // Timeouts while reading the response try the next link instead of leaving a partial file
+            if let Err(e) = std::io::copy(&mut http::Throttled(&mut resp), tmpfile.as_file_mut()) {
+                error = format!("Could not download {}: {}", url, e);
+                continue;
+            }
 
-            let size = std::fs::metadata(&file_path).unwrap().len();
+            size = tmpfile.as_file().metadata().map_or(0, |m| m.len());
             // Stupid solution where image must be larger than 1 kB as not to download a 404 page or something as an image
             // TODO: fix this, possible to check if image is valid?
             debug_output("size", &size.to_string());
-            // Break if downloaded file contains data
-            if file_path.exists() && size > 1000 {
+            // Keep if downloaded file contains data
+            if size > 1000 {
+                if let Err(e) = tmpfile.persist(&file_path) {
+                    error = format!("Could not save {}: {}", file_path.display(), e.error);
+                    continue;
+                }
+                downloaded = Some(i);
                 break;
             }
-            // TODO: Remove old file less than 1000 bytes here, since it will stay if next image has another file extension
+            error = format!("{} returned only {} bytes", url, size);
         }
 
-        println!("{}", Green.paint("Done"));
+        match downloaded {
+            Some(i) => {
+                statusln!("{}{}", line, Green.paint("Done"));
+                quota::add(size);
+                report::emit(Event::Downloaded {
+                    url: img_links[i].clone(),
+                    path: file_path.clone(),
+                    bytes: size,
+                    duration: started.elapsed().as_secs_f64(),
+                });
+            },
+            None => {
+                statusln!("{}{} {}", line, Red.paint("Failed:"), error);
+                report::emit(Event::Failed {
+                    url: img.as_ref().to_string(),
+                    path: if file_path.is_file() { Some(file_path.clone()) } else { None },
+                    error,
+                    duration: started.elapsed().as_secs_f64(),
+                });
+                // Files which could not be downloaded are not linked or dated
+                if ! file_path.is_file() {
+                    return None;
+                }
+            },
+        }
+
+        // Records where the iqdb image came from when the original is kept
+        if let (Some(original), Some(i)) = (original, downloaded) {
+            if let Some((source, similarity)) = img_sources.get(i) {
+                write_provenance(dir_path.as_ref(), &file_path, original, source, similarity.as_deref());
+            }
+        }
     }
     return Some(file_path);
 }
 
-/// Returns all lines in a file as a Vector
-fn file_to_vec() -> Result<Vec<(String, String)>, String> {
-    let res: Vec<(String, String)>;
-    let contents;
-    contents = match read_to_string("threads.txt") {
-        Ok(contents) => contents,
-        Err(_) => {
-            println!("threads.txt does not exist, creating {}", Green.paint("Done"));
-            std::fs::write("threads.txt", "").expect("Could not create threads.txt file");
-            String::new()
-        }
+/// Records where an iqdb image came from in `sources.txt` in `dir_path`, replacing the line of the image if it was downloaded before.
+/// Each line is `{image};{original};{source page};{similarity};{original dimensions};{image dimensions};{phash distance}`
+fn write_provenance(dir_path: &Path, file_path: &Path, original: &Path, source: &str, similarity: Option<&str>) {
+    let dimensions = |path: &Path| match imagesize::size(path) {
+        Ok(s) => format!("{}x{}", s.width, s.height),
+        Err(_) => "unknown".to_string(),
+    };
+    let file_name = |path: &Path| path.file_name().unwrap().to_string_lossy().to_string();
+
+    // Number of differing bits between the perceptual hashes, a low distance means the image really is the original
+    let distance = match (similar::hash_image(original, similar::Algorithm::Perceptual), similar::hash_image(file_path, similar::Algorithm::Perceptual)) {
+        (Ok(a), Ok(b)) => similar::distance(a, b).to_string(),
+        _ => "unknown".to_string(),
     };
-    res = contents
-                // Remove whitespace and special characters
-                .split("\n").map(|s| s.trim().to_string()).filter(|s| ! s.is_empty())
-                // Convert format to tuple
-                .map(|s| {
-                    let t: (&str, &str) = s.split_once(";").expect("Could not split String");
-                    // Convert (&str, &str) to (String, String)
-                    (t.0.to_string(), t.1.to_string())
-                }).collect();
-    return Ok(res);
-}
 
-/// Creates `Response` object from given url. `None` if no response were given from site.
-fn get_response(url: &str) -> Option<Response> {
-    let client: Client;
+    let name = file_name(file_path);
+    let line = format!("{};{};{};{};{};{};{}",
+        name,
+        file_name(original),
+        source,
+        similarity.unwrap_or("unknown"),
+        dimensions(original),
+        dimensions(file_path),
+        distance);
+    debug_output("provenance", &line);
 
-    if url.contains("iqdb.org") {
-        // Ignore timeout for iqdb since it can take a while without being an error
-        client = Client::builder().build().unwrap();
+    // Images downloaded again with --override keep one line, which is kept in its place
+    let path = dir_path.join("sources.txt");
+    let mut lines: Vec<String> = std::fs::read_to_string(&path).unwrap_or_default().lines().map(String::from).collect();
+    match lines.iter_mut().find(|l| l.split(';').next() == Some(name.as_str())) {
+        Some(existing) => *existing = line,
+        None => lines.push(line),
     }
-    else {
-        client = Client::builder().timeout(time::Duration::from_secs(5)).build().unwrap();
+    if let Err(e) = std::fs::write(&path, lines.join("\n") + "\n") {
+        statusln!("{} Could not write {}: {}", Red.paint("Error:"), path.display(), e);
     }
+}
+
+/// Creates `Response` object from given url, retrying timeouts, connection errors and 429 and 5xx statuses.
+/// Error statuses and failures after the last attempt are returned as `RequestError`
+fn get_response(url: &str) -> Result<Response, RequestError> {
+    let settings = config::settings();
+    let policy = http::RetryPolicy::from_settings(settings);
 
-    let resp: Response;
-    let mut i = 0;
+    // Sites answer with 304 if the page has not changed since the date
+    let if_modified_since = http::take_if_modified_since(url);
 
+    let mut attempt: u32 = 1;
     loop {
-        resp = match client.get(url).header(USER_AGENT, USER_AGENT_VALUE).send() {
-            Ok(r) => r,
-            Err(_) => {
-                //TODO: is this block really necessary if a timeout is set?
-                i += 1;
-                if i == 2 {
-                    println!("{} Could not get a response from {}, continuing", Yellow.paint("Warning:"), url);
-                    return None
+        http::wait_for_rate_limit(url);
+        let mut request = http::request(url);
+        if let Some(date) = &if_modified_since {
+            request = request.header(IF_MODIFIED_SINCE, date);
+        }
+        let delay = match request.send() {
+            Ok(resp) if resp.status().is_success() => {
+                if let Some(date) = resp.headers().get(LAST_MODIFIED).and_then(|d| d.to_str().ok()) {
+                    http::set_last_modified(url, date);
                 }
-                println!("Could not get response, retrying...");
-                thread::sleep(time::Duration::from_secs(1));
-                continue
-            }
+                return Ok(resp);
+            },
+            Ok(resp) => {
+                let status = resp.status();
+                if attempt >= policy.attempts || ! http::is_retried_status(status) {
+                    debug_output("status error on", url);
+                    return Err(RequestError::Status { url: url.to_string(), attempts: attempt, status });
+                }
+                let delay = policy.delay_for(attempt, Some(&resp));
+                statusln!("{} returned {}, retrying in {:.1}s", url, status, delay.as_secs_f64());
+                delay
+            },
+            Err(e) => {
+                if attempt >= policy.attempts || ! http::is_retried_error(&e) {
+                    return Err(RequestError::NoResponse { url: url.to_string(), attempts: attempt, error: e.to_string() });
+                }
+                let delay = policy.delay_for(attempt, None);
+                statusln!("Could not get a response from {}, retrying in {:.1}s", url, delay.as_secs_f64());
+                delay
+            },
         };
-        // If this is reached, then match returned Ok()
-        break
+        thread::sleep(delay);
+        attempt += 1;
     }
-    Some(resp)
 }
 
 // BUG: Handle redirects in loop to get to pointed site. (for archived.moe which redirects to other sites)
 /// Returns HTML Document of given site. 
-/// Error contains `Response` if it could be retrieved, otherwise `None`
-fn get_html(url: &str) -> Result<Document, Option<Response>> {
+/// Error says whether a response was received and which status it had
+fn get_html(url: &str) -> Result<Document, RequestError> {
     
-    let mut resp: Response = match get_response(&url) {
-        Some(r) => r,
-        None => return Err(None),
-    };
-
-    if !resp.status().is_success() {
-        debug_output("status error on", url);
-        return Err(Some(resp))
-    }
+    let mut resp: Response = get_response(url)?;
 
     let document = match Document::from_read(&mut resp) {
         Ok(d) => d,
         Err(e) => {
-            println!("{:#?}", resp); 
-            println!("{:#?}", e); 
-            return Err(None)
+            debug_output("response", &format!("{:#?}", resp));
+            return Err(RequestError::Invalid { url: url.to_string(), error: e.to_string() })
         },
     };
 
     return Ok(document);
 }
 
+/// Returns the links to all matches found by iqdb together with the similarity given for each match
+fn get_iqdb_matches(url: &str) -> Result<Vec<(String, Option<String>)>, RequestError> {
+    let doc = get_html(url)?;
+
+    // Each match is displayed in its own table, which contains a cell like "94% similarity"
+    let mut similarities: Vec<(String, String)> = Vec::new();
+    for table in doc.find(Name("table")) {
+        let similarity = table.find(Name("td"))
+            .map(|n| n.text())
+            .find(|t| t.ends_with("similarity"));
+        if let Some(similarity) = similarity {
+            let similarity = similarity.split_whitespace().next().unwrap_or_default().to_string();
+            table.find(Name("a"))
+                .filter_map(|n| n.attr("href"))
+                .for_each(|n| similarities.push((n.to_string(), similarity.clone())));
+        }
+    }
+
+    let links: Vec<String> = doc.find(Name("a"))
+        .filter_map(|n| n.attr("href"))
+        .map(|n| n.to_string())
+        .collect();
+
+    Ok(links
+        // Get all links before the '#' link (since all after are irrelevant)
+        .split(|n| n == "#")
+        .next().unwrap()
+        .iter()
+        // Remove first element ('/' link)
+        .filter(|n| *n != "/")
+        .map(|n| {
+            let similarity = similarities.iter().find(|s| &s.0 == n).map(|s| s.1.clone());
+            // Conditioned because sometimes links already starts with "https://"
+            (if n.starts_with("//") { n.replace("//", "https://") } else { n.clone() }, similarity)
+        })
+        .collect())
+}
+
 /// Returns Vector with all links found in anchor tags on given site
-/// Error contains `Response` if it could be retrieved, otherwise `None`
-fn get_links(url: &str) -> Result<Vec<String>, Option<Response>> {
+fn get_links(url: &str) -> Result<Vec<String>, RequestError> {
     let mut res: Vec<String> = Vec::new();
 
     match get_html(url) {
@@ -593,15 +1450,18 @@ fn get_links(url: &str) -> Result<Vec<String>, Option<Response>> {
     return Ok(res)
 }
 
-/// Returns a folder name with the "{thread-id} - {thread subject}" pattern
-/// Error contains `Response` if it could be retrieved, otherwise `None`
-fn get_name<S: AsRef<str>>(url: S, only_thread_number: bool) -> Result<String, Option<Response>> {
+/// Returns the thread number and subject of the thread, which are used to name its directory
+fn get_name<S: AsRef<str>>(url: S) -> Result<(String, String), RequestError> {
     debug_output("get_name url", url.as_ref());
     let doc = match get_html(&url.as_ref()) {
         Ok(d) => d,
         Err(r) => return Err(r)
     };
-        let thread_number: String = url.as_ref().split("/").filter(|&s| !s.is_empty()).collect::<Vec<_>>().last().unwrap().to_string();
+        // Links may end with a slug or a post after the thread number
+        let thread_number: String = match ThreadRef::parse(url.as_ref()) {
+            Ok(thread) => thread.no.to_string(),
+            Err(_) => url.as_ref().split('/').rfind(|s| !s.is_empty()).unwrap_or_default().to_string(),
+        };
         let mut subject_node = doc.find(Class("subject")).collect::<Vec<_>>();
 
         if subject_node.first().is_none() || subject_node.first().unwrap().text().is_empty() {
@@ -617,24 +1477,9 @@ fn get_name<S: AsRef<str>>(url: S, only_thread_number: bool) -> Result<String, O
             subject = "title".to_string();
         }
         else {
-            subject = subject_node.first().unwrap().text().replace("/", " ");
-        }
-
-        if only_thread_number {
-            return Ok(format!("{}", thread_number))
+            // Characters which can not be used in directory names are replaced by naming::sanitize
+            subject = subject_node.first().unwrap().text();
         }
-        else {
-            return Ok(format!("{} - {}", thread_number, subject));
-        }
-}
-
-//BUG: When saving to file that has been updated those updates are lost. Read from file first and include the new records
-/// Removes existing file and writes links from Vec with the format (String, String) to it
-fn vec_to_file(vec: Vec<(String, String)>) {
-    // std::fs::remove_file("threads.txt");
-    let mut file = File::create("threads.txt").expect("Could not create file");
 
-	for l in vec.iter() {
-		file.write(format!("{};{}\n", l.0, l.1).as_bytes()).expect("Could not write to file");
-	}
+        return Ok((thread_number, subject));
 }
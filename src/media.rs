@@ -0,0 +1,158 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files which are downloaded from threads
+pub const EXTENSIONS: [&str; 7] = [".jpg", ".jpeg", ".png", ".gif", ".webm", ".mp4", ".pdf"];
+
+/// A file uploaded to 4chan. Files are named after the `tim` upload timestamp and the extension,
+/// thumbnails are named after the same timestamp with an 's' appended and are always jpg images
+#[derive(Debug, Clone, PartialEq)]
+pub struct Media {
+    /// Upload timestamp used as file name by 4chan and its archives
+    pub tim: u64,
+    /// Extension of the file including the leading '.'
+    pub ext: String,
+    /// true if the link pointed to the thumbnail of the file
+    pub thumbnail: bool,
+}
+
+impl Media {
+    /// Parses the last segment of a link or path, such as `1620000000000.png` or `1620000000000s.jpg`.
+    /// Returns `None` if the name does not follow the 4chan naming scheme
+    pub fn from_link<S: AsRef<str>>(link: S) -> Option<Media> {
+        let name = link.as_ref()
+            // Ignore query strings and fragments
+            .split(['?', '#']).next()?
+            .rsplit('/').next()?;
+        let (stem, ext) = name.split_at(name.rfind('.')?);
+        if ext.len() < 2 {
+            return None;
+        }
+
+        let (tim, thumbnail) = match stem.strip_suffix('s') {
+            Some(tim) => (tim, true),
+            None => (stem, false),
+        };
+        if tim.is_empty() || !tim.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        Some(Media {
+            tim: tim.parse().ok()?,
+            ext: ext.to_lowercase(),
+            thumbnail,
+        })
+    }
+
+    /// Name of the file without extension, which is shared between the original file and its thumbnail
+    pub fn stem(&self) -> String {
+        self.tim.to_string()
+    }
+
+    /// Name of the file as saved by 4chan, `{tim}s.jpg` for thumbnails and `{tim}{ext}` otherwise
+    pub fn file_name(&self) -> String {
+        if self.thumbnail {
+            self.thumbnail_name()
+        }
+        else {
+            format!("{}{}", self.tim, self.ext)
+        }
+    }
+
+    /// Name of the thumbnail of the file
+    pub fn thumbnail_name(&self) -> String {
+        format!("{}s.jpg", self.tim)
+    }
+
+    /// Returns the original file of a thumbnail.
+    /// The extension of the original can not be known from the thumbnail, so the thumbnail extension is kept
+    pub fn original(&self) -> Media {
+        Media {
+            thumbnail: false,
+            ..self.clone()
+        }
+    }
+}
+
+/// Returns a file in the directory named `stem` with any extension, such as an image found on iqdb which can have another extension than the thumbnail.
+/// Only whole stems match, so `123` does not match `1234.jpg` or `123s.jpg`
+pub fn find_with_stem(dir: &Path, stem: &str) -> Option<PathBuf> {
+    read_dir(dir).ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.is_file() && path.file_stem().is_some_and(|s| s.to_string_lossy() == stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+
+    #[test]
+    fn thumbnail_is_named_after_its_original() {
+        let media = Media::from_link("https://i.4cdn.org/g/1620000000000s.jpg").unwrap();
+        assert_eq!(media, Media { tim: 1620000000000, ext: ".jpg".to_string(), thumbnail: true });
+        assert_eq!(media.original().file_name(), "1620000000000.jpg");
+        assert_eq!(media.stem(), "1620000000000");
+        assert_eq!(media.file_name(), "1620000000000s.jpg");
+    }
+
+    #[test]
+    fn original_is_not_a_thumbnail() {
+        let media = Media::from_link("https://i.4cdn.org/g/1620000000000.png").unwrap();
+        assert!(!media.thumbnail);
+        assert_eq!(media.file_name(), "1620000000000.png");
+        assert_eq!(media.thumbnail_name(), "1620000000000s.jpg");
+    }
+
+    #[test]
+    fn only_a_trailing_s_marks_a_thumbnail() {
+        // Boards and directories named s are not part of the name
+        assert_eq!(Media::from_link("https://i.4cdn.org/s/123s.jpg").unwrap().original().file_name(), "123.jpg");
+        assert_eq!(Media::from_link("https://archive.org/s/thumbs/123.jpg").unwrap().file_name(), "123.jpg");
+        assert_eq!(Media::from_link("123ss.jpg"), None);
+        assert_eq!(Media::from_link("12s3.jpg"), None);
+        assert_eq!(Media::from_link("s123.jpg"), None);
+        assert_eq!(Media::from_link("s.jpg"), None);
+        assert_eq!(Media::from_link("sss.jpg"), None);
+    }
+
+    #[test]
+    fn query_strings_and_fragments_are_ignored() {
+        assert_eq!(Media::from_link("https://i.4cdn.org/g/123s.jpg?v=2").unwrap().original().file_name(), "123.jpg");
+        assert_eq!(Media::from_link("https://i.4cdn.org/g/123.webm#t=10").unwrap().file_name(), "123.webm");
+        assert_eq!(Media::from_link("https://i.4cdn.org/g/123s.jpg?a=1#b").unwrap().stem(), "123");
+        assert_eq!(Media::from_link("https://example.com/view?file=123s.jpg"), None);
+    }
+
+    #[test]
+    fn extensions_are_lowercase() {
+        assert_eq!(Media::from_link("123.JPG").unwrap().ext, ".jpg");
+    }
+
+    #[test]
+    fn other_names_are_not_4chan_media() {
+        assert_eq!(Media::from_link("https://example.com/image.jpg"), None);
+        assert_eq!(Media::from_link("https://example.com/photo_123.png"), None);
+        assert_eq!(Media::from_link("https://example.com/123"), None);
+        assert_eq!(Media::from_link("123."), None);
+        assert_eq!(Media::from_link(".jpg"), None);
+        assert_eq!(Media::from_link(""), None);
+        assert_eq!(Media::from_link("99999999999999999999999.jpg"), None);
+    }
+
+    #[test]
+    fn existing_file_needs_the_whole_stem() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("1234.jpg"), "").unwrap();
+        write(dir.path().join("123s.jpg"), "").unwrap();
+        write(dir.path().join("0123.jpg"), "").unwrap();
+        create_dir(dir.path().join("123")).unwrap();
+        assert_eq!(find_with_stem(dir.path(), "123"), None);
+
+        write(dir.path().join("123.png"), "").unwrap();
+        assert_eq!(find_with_stem(dir.path(), "123"), Some(dir.path().join("123.png")));
+        assert_eq!(find_with_stem(dir.path(), "12"), None);
+        assert_eq!(find_with_stem(&dir.path().join("missing"), "123"), None);
+    }
+}
@@ -0,0 +1,151 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::library::Layout;
+use crate::thread_ref::ThreadRef;
+
+/// Longest file name in bytes allowed by most filesystems
+const MAX_NAME_BYTES: usize = 255;
+
+/// Characters which are not allowed in file names on Windows, exFAT and NTFS
+const PORTABLE_RESERVED_CHARS: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// File names which are reserved for devices on Windows, with or without an extension
+const PORTABLE_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Returns the template used when `--dir-template` is not given
+pub fn default_template(layout: Layout) -> &'static str {
+    match layout {
+        Layout::Flat => "{no} - {subject}",
+        Layout::Nested => "{board}/{no} - {subject}",
+    }
+}
+
+/// Returns the template in the settings, or the default template of the layout
+fn template() -> &'static str {
+    let settings = crate::config::settings();
+    settings.dir_template.as_deref()
+        .unwrap_or_else(|| default_template(settings.layout.unwrap_or(Layout::Flat)))
+}
+
+/// Returns the directory of a thread relative to the root from the template in the settings
+pub fn thread_dir(url: &str, no: &str, subject: &str) -> String {
+    let board = crate::api::board(url).unwrap_or_default();
+    render(template(), &board, no, subject, crate::config::settings().portable_names == Some(true))
+}
+
+/// Returns the directory of a thread given with `--dir` relative to the root. The name replaces the last part of the template,
+/// and the directories before it like `{board}/` are kept so the thread is saved next to other threads
+pub fn named_thread_dir(url: &str, name: &str) -> String {
+    let portable = crate::config::settings().portable_names == Some(true);
+    let board = crate::api::board(url).unwrap_or_default();
+    let no = ThreadRef::parse(url).map(|t| t.no.to_string()).unwrap_or_default();
+    let parents = template().rsplit_once('/').map_or("", |(parents, _)| parents);
+
+    let mut dirs = render_parts(parents, &board, &no, "", portable);
+    dirs.push(sanitize(name, portable));
+    dirs.retain(|dir| !dir.is_empty());
+    dirs.join("/")
+}
+
+/// Returns the directory of a thread relative to the root from a template like `{board}/{no} - {subject}`.
+/// Each `/` separated part of the template is made a safe directory name, empty parts are left out.
+/// Subjects are read from the text of the thread page, which has its HTML entities decoded already
+pub fn render(template: &str, board: &str, no: &str, subject: &str, portable: bool) -> String {
+    let dirs = render_parts(template, board, no, subject, portable);
+    if dirs.is_empty() {
+        sanitize(no, portable)
+    }
+    else {
+        dirs.join("/")
+    }
+}
+
+/// Returns the directories of each part of the template which is not empty
+fn render_parts(template: &str, board: &str, no: &str, subject: &str, portable: bool) -> Vec<String> {
+    template.split('/')
+        .map(|part| part
+            .replace("{board}", board)
+            .replace("{no}", no)
+            .replace("{subject}", subject))
+        .map(|part| sanitize(&part, portable))
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+/// Returns a name which can be used for a single directory or file.
+/// With `portable` the name is also safe on Windows, exFAT and NTFS
+pub fn sanitize(name: &str, portable: bool) -> String {
+    let name: String = name.nfc()
+        .map(|c| match c {
+            // Slashes were replaced with spaces in subjects before templates
+            '/' => ' ',
+            c if c.is_control() => '_',
+            c if portable && PORTABLE_RESERVED_CHARS.contains(&c) => '_',
+            c => c,
+        })
+        .collect();
+
+    let mut name = truncate(name.trim(), MAX_NAME_BYTES).trim_end().to_string();
+    if portable {
+        // Windows removes trailing dots and spaces, which would give a different name than the one saved
+        name = name.trim_end_matches(['.', ' ']).to_string();
+        let stem = name.split('.').next().unwrap_or_default().trim_end().to_uppercase();
+        if PORTABLE_RESERVED_NAMES.contains(&stem.as_str()) {
+            name.insert(0, '_');
+            name = truncate(&name, MAX_NAME_BYTES).to_string();
+        }
+    }
+    // "." and ".." would point to other directories
+    if name.chars().all(|c| c == '.') {
+        name = name.replace('.', "_");
+    }
+    name
+}
+
+/// Returns the longest start of the text which is at most `max` bytes, without splitting a character
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_byte_subject_is_truncated_at_a_character() {
+        // 'é' is 2 bytes, so 255 bytes end in the middle of the 128th character
+        let name = sanitize(&"é".repeat(200), false);
+        assert_eq!(name.len(), 254);
+        assert_eq!(name, "é".repeat(127));
+    }
+
+    #[test]
+    fn reserved_windows_names_are_prefixed() {
+        assert_eq!(sanitize("CON", true), "_CON");
+        assert_eq!(sanitize("nul.txt", true), "_nul.txt");
+        assert_eq!(sanitize("CON", false), "CON");
+        assert_eq!(sanitize("CONTACT", true), "CONTACT");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_removed_from_portable_names() {
+        assert_eq!(sanitize("subject. . ", true), "subject");
+        assert_eq!(sanitize("subject...", false), "subject...");
+    }
+
+    #[test]
+    fn slash_in_subject_does_not_add_a_directory() {
+        assert_eq!(render("{board}/{no} - {subject}", "g", "123", "AC/DC", false), "g/123 - AC DC");
+    }
+}
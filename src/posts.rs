@@ -0,0 +1,163 @@
+use select::{document::Document, predicate::{Class, Name}};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use crate::api::{Post, Thread};
+
+/// Name of the file posts are saved to in the thread directory
+pub const FILE_NAME: &str = "thread.json";
+
+/// Thread saved to `thread.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedThread {
+    pub board: String,
+    pub no: u64,
+    pub url: String,
+    pub posts: Vec<SavedPost>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPost {
+    pub no: u64,
+    /// Unix timestamp of when the post was made
+    pub time: i64,
+    pub name: Option<String>,
+    pub tripcode: Option<String>,
+    pub poster_id: Option<String>,
+    pub subject: Option<String>,
+    pub comment: String,
+    pub comment_html: String,
+    /// Posts in the thread this post links to
+    pub replies_to: Vec<u64>,
+    /// Posts in the thread linking to this post
+    pub replied_by: Vec<u64>,
+    pub attachment: Option<Attachment>,
+    /// true if the post is no longer in the thread
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Name of the file when it was uploaded
+    pub original_name: String,
+    /// Name of the file in the thread directory
+    pub file_name: String,
+    pub size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub md5: Option<String>,
+    pub deleted: bool,
+    pub spoiler: bool,
+}
+
+impl From<&Post> for SavedPost {
+    fn from(post: &Post) -> Self {
+        let comment_html = post.com.clone().unwrap_or_default();
+
+        SavedPost {
+            no: post.no,
+            time: post.time,
+            name: post.name.clone(),
+            tripcode: post.trip.clone(),
+            poster_id: post.id.clone(),
+            subject: post.sub.clone(),
+            comment: comment_text(&comment_html),
+            replies_to: reply_links(&comment_html),
+            replied_by: Vec::new(),
+            comment_html,
+            attachment: post.media().map(|media| Attachment {
+                original_name: format!("{}{}", post.filename.clone().unwrap_or_default(), media.ext),
+                file_name: media.file_name(),
+                size: post.fsize,
+                width: post.w,
+                height: post.h,
+                md5: post.md5.clone(),
+                deleted: post.filedeleted == 1,
+                spoiler: post.spoiler == 1,
+            }),
+            deleted: false,
+        }
+    }
+}
+
+/// Returns the comment as plain text
+pub fn comment_text(html: &str) -> String {
+    // Line breaks are given as <br> tags which have no text
+    let doc = Document::from(html.replace("<br>", "\n").as_str());
+    doc.find(Name("body")).next().map(|n| n.text()).unwrap_or_default()
+}
+
+/// Returns the numbers of all posts in the same thread linked to in the comment
+pub fn reply_links(html: &str) -> Vec<u64> {
+    let doc = Document::from(html);
+    let mut links: Vec<u64> = doc.find(Class("quotelink"))
+        .filter_map(|n| n.attr("href"))
+        // Links to posts in other threads include the path to the thread before '#'
+        .filter_map(|n| n.strip_prefix("#p"))
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    links.sort_unstable();
+    links.dedup();
+    links
+}
+
+/// Returns all posts in the thread with backlinks to the posts replying to them
+pub fn thread_posts(thread: &Thread) -> Vec<SavedPost> {
+    let mut posts: Vec<SavedPost> = thread.posts.iter().map(SavedPost::from).collect();
+    link_replies(&mut posts);
+    posts
+}
+
+/// Fills in `replied_by` of all posts from the links in the other posts
+fn link_replies(posts: &mut [SavedPost]) {
+    for post in posts.iter_mut() {
+        post.replied_by.clear();
+    }
+    let links: Vec<(u64, u64)> = posts.iter()
+        .flat_map(|p| p.replies_to.iter().map(move |to| (p.no, *to)))
+        .collect();
+    for (from, to) in links {
+        if let Some(post) = posts.iter_mut().find(|p| p.no == to) {
+            post.replied_by.push(from);
+        }
+    }
+}
+
+/// Writes all posts in the thread to `thread.json` in `dir_path`.
+/// Posts already in the file which are no longer in the thread are kept and marked as deleted
+pub fn save_posts(dir_path: &Path, url: &str, board: &str, no: u64, thread: &Thread) {
+    let file_path = dir_path.join(FILE_NAME);
+
+    let mut posts: Vec<SavedPost> = thread.posts.iter().map(SavedPost::from).collect();
+
+    if let Some(saved) = read_posts(dir_path) {
+        for mut post in saved.posts {
+            if !posts.iter().any(|p| p.no == post.no) {
+                post.deleted = true;
+                posts.push(post);
+            }
+        }
+    }
+    posts.sort_by_key(|p| p.no);
+    // Backlinks are built from the links in all posts, including deleted ones
+    link_replies(&mut posts);
+
+    let saved = SavedThread {
+        board: board.to_string(),
+        no,
+        url: url.to_string(),
+        posts,
+    };
+    write(&file_path, serde_json::to_string_pretty(&saved).unwrap()).expect("Could not write thread.json");
+}
+
+/// Reads the posts saved to `thread.json` in `dir_path`, `None` if there are none
+pub fn read_posts(dir_path: &Path) -> Option<SavedThread> {
+    let file_path = dir_path.join(FILE_NAME);
+    let contents = read_to_string(&file_path).ok()?;
+    serde_json::from_str(&contents).map_err(|e| {
+        crate::debug_output("json error", &format!("Could not read {}: {}", file_path.display(), e));
+    }).ok()
+}
@@ -0,0 +1,100 @@
+use serde::Deserialize;
+use std::fs::{read_dir, symlink_metadata};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Bytes used by the current thread and by the library, counted when a quota is set
+static USAGE: Mutex<Usage> = Mutex::new(Usage { thread: 0, library: None });
+
+struct Usage {
+    thread: u64,
+    /// Counted the first time the library quota is checked
+    library: Option<u64>,
+}
+
+/// What to do with a thread when a quota is reached
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Skip new files while the quota is reached
+    #[default]
+    Skip,
+    /// Pause the thread when its quota is reached, and stop all downloads when the library quota is reached
+    StopWatching,
+}
+
+/// Limit a file would exceed, with a message saying why
+#[derive(Debug)]
+pub enum Limit {
+    /// Less free space than the reserve would be left on the disk
+    DiskSpace(String),
+    /// The files of the thread would be larger than `thread_quota`
+    Thread(String),
+    /// The files of the library would be larger than `library_quota`
+    Library(String),
+}
+
+/// Starts counting the files of a thread downloaded to the directory
+pub fn start_thread(dir_path: &Path) {
+    let settings = crate::config::settings();
+    USAGE.lock().unwrap().thread = if settings.thread_quota.is_some() { dir_size(dir_path) } else { 0 };
+}
+
+/// Counts a downloaded file for the quotas
+pub fn add(bytes: u64) {
+    let mut usage = USAGE.lock().unwrap();
+    usage.thread += bytes;
+    if let Some(library) = usage.library.as_mut() {
+        *library += bytes;
+    }
+}
+
+/// Checks that a file of the given size can be downloaded to the directory without going below the reserve or over a quota.
+/// Files of an unknown size are checked as empty files, so the last file can go over a quota
+pub fn check(dir_path: &Path, size: Option<u64>) -> Result<(), Limit> {
+    let settings = crate::config::settings();
+    let size = size.unwrap_or(0);
+
+    // Free space is only checked when a reserve is set, disks which cannot be checked are not limited
+    if let (Some(reserve), Ok(available)) = (settings.disk_reserve, fs2::available_space(dir_path)) {
+        if available.saturating_sub(size) < reserve {
+            return Err(Limit::DiskSpace(format!("Only {} of disk space is left, which is less than the reserve of {}",
+                format_size(available), format_size(reserve))));
+        }
+    }
+
+    let mut usage = USAGE.lock().unwrap();
+    if let Some(quota) = settings.thread_quota {
+        if usage.thread + size > quota {
+            return Err(Limit::Thread(format!("The thread uses {} of its quota of {}", format_size(usage.thread), format_size(quota))));
+        }
+    }
+    if let Some(quota) = settings.library_quota {
+        let library = *usage.library.get_or_insert_with(|| dir_size(crate::config::root()));
+        if library + size > quota {
+            return Err(Limit::Library(format!("The library uses {} of its quota of {}", format_size(library), format_size(quota))));
+        }
+    }
+    Ok(())
+}
+
+/// Formats the size in megabytes like the summary, or in gigabytes for sizes of disks
+fn format_size(bytes: u64) -> String {
+    let megabytes = bytes as f64 / (1024.0 * 1024.0);
+    if megabytes >= 1024.0 { format!("{:.1} GB", megabytes / 1024.0) } else { format!("{:.1} MB", megabytes) }
+}
+
+/// Returns the size of all files in the directory and its subdirectories. Links are not followed, and hard links are counted for each link
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match read_dir(path) {
+        Ok(e) => e,
+        Err(_) => return 0,
+    };
+    entries.flatten()
+        .map(|entry| match symlink_metadata(entry.path()) {
+            Ok(m) if m.is_dir() => dir_size(&entry.path()),
+            Ok(m) if m.is_file() => m.len(),
+            _ => 0,
+        })
+        .sum()
+}
@@ -0,0 +1,287 @@
+use serde::Serialize;
+use crate::color::Color::*;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+/// Exit code when all files were downloaded or already existed
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code when some threads or files could not be downloaded, or downloads were stopped since the disk is almost full
+pub const EXIT_PARTIAL_FAILURE: i32 = 2;
+/// Exit code when something failed and no file could be downloaded
+pub const EXIT_FAILURE: i32 = 3;
+/// Exit code when nothing failed but a thread was not found, which means it has been archived or deleted
+pub const EXIT_NOT_FOUND: i32 = 4;
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+/// When the command started, used for the total time
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static STATE: Mutex<State> = Mutex::new(State {
+    thread: String::new(),
+    started: None,
+    files: Files { queued: 0, downloaded: 0, exists: 0, failed: 0, iqdb_not_found: 0, over_quota: 0 },
+    bytes: 0,
+    threads: Vec::new(),
+    events: Vec::new(),
+});
+
+/// Format of the output of `download` and `update`, given with `--output`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Messages for people
+    Text,
+    /// A JSON array of all events, written when the command is done
+    Json,
+    /// One JSON event per line, written as soon as it happens
+    Ndjson,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            "ndjson" => Ok(Output::Ndjson),
+            _ => Err(format!("'{}' is not an output format, expected text, json or ndjson", s)),
+        }
+    }
+}
+
+/// Something which happened to a file or thread, written with `--output json` and `--output ndjson`.
+/// Events of files also have the url of the thread they belong to
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// The file is about to be downloaded, or skipped if it exists
+    Queued { url: String, path: PathBuf },
+    /// The file was downloaded before
+    Exists { url: String, path: PathBuf, bytes: u64 },
+    /// The file was downloaded, duration is in seconds
+    Downloaded { url: String, path: PathBuf, bytes: u64, duration: f64 },
+    /// The file could not be downloaded from any link
+    Failed { url: String, path: Option<PathBuf>, error: String, duration: f64 },
+    /// No match for the thumbnail was found on iqdb
+    IqdbNotFound { url: String, iqdb: String },
+    /// The file was not downloaded since it would go over the quota of the thread or library
+    OverQuota { url: String, path: PathBuf, reason: String },
+    /// All files of the thread are done, or the thread could not be read
+    Thread(ThreadSummary),
+    /// All threads are done, written last
+    Summary {
+        threads: usize,
+        files: Files,
+        bytes: u64,
+        duration: f64,
+        exit_code: i32,
+    },
+}
+
+/// What happened to the files of a thread
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadSummary {
+    pub url: String,
+    pub dir: Option<String>,
+    /// `done`, `not-found`, `over-quota`, `stopped` or `failed`
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub files: Files,
+    pub bytes: u64,
+    pub duration: f64,
+}
+
+/// Number of files of a thread by what happened to them
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Files {
+    pub queued: u64,
+    pub downloaded: u64,
+    pub exists: u64,
+    pub failed: u64,
+    pub iqdb_not_found: u64,
+    pub over_quota: u64,
+}
+
+impl Files {
+    fn add(&mut self, other: Files) {
+        self.queued += other.queued;
+        self.downloaded += other.downloaded;
+        self.exists += other.exists;
+        self.failed += other.failed;
+        self.iqdb_not_found += other.iqdb_not_found;
+        self.over_quota += other.over_quota;
+    }
+}
+
+/// Thread which files are counted for, threads which are done and events waiting to be written with `--output json`
+struct State {
+    thread: String,
+    started: Option<Instant>,
+    files: Files,
+    bytes: u64,
+    threads: Vec<ThreadSummary>,
+    events: Vec<serde_json::Value>,
+}
+
+pub fn set_output(output: Output) {
+    OUTPUT.set(output).expect("Output format is already set");
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn output() -> Output {
+    *OUTPUT.get().unwrap_or(&Output::Text)
+}
+
+/// Returns true if messages are written to standard output, false if standard output is used for events
+pub fn is_text() -> bool {
+    output() == Output::Text
+}
+
+/// Starts counting the files of the thread, events of files belong to it until `end_thread` is called
+pub fn start_thread(url: &str) {
+    let mut state = STATE.lock().unwrap();
+    state.thread = url.to_string();
+    state.started = Some(Instant::now());
+    state.files = Files::default();
+    state.bytes = 0;
+}
+
+/// Counts the file of the event for the current thread and writes the event
+pub fn emit(event: Event) {
+    let mut state = STATE.lock().unwrap();
+    match &event {
+        Event::Queued { .. } => state.files.queued += 1,
+        Event::Exists { .. } => state.files.exists += 1,
+        Event::Downloaded { bytes, .. } => {
+            state.files.downloaded += 1;
+            state.bytes += bytes;
+        },
+        Event::Failed { .. } => state.files.failed += 1,
+        Event::IqdbNotFound { .. } => state.files.iqdb_not_found += 1,
+        Event::OverQuota { .. } => state.files.over_quota += 1,
+        Event::Thread(_) | Event::Summary { .. } => (),
+    }
+    if is_text() {
+        return;
+    }
+
+    let mut value = serde_json::to_value(&event).unwrap();
+    if !matches!(event, Event::Thread(_) | Event::Summary { .. }) {
+        value.as_object_mut().unwrap().insert("thread".to_string(), state.thread.clone().into());
+    }
+    match output() {
+        Output::Ndjson => {
+            println!("{}", value);
+            // Events are read while the download is running, so they are not kept in the buffer
+            let _ = std::io::stdout().flush();
+        },
+        _ => state.events.push(value),
+    }
+}
+
+/// Writes the summary event of the current thread and keeps it for the summary, which is returned. `status` is `done`, `not-found`, `over-quota`, `stopped` or `failed`
+pub fn end_thread(dir: Option<&str>, status: &'static str, error: Option<String>) -> ThreadSummary {
+    let summary = {
+        let mut state = STATE.lock().unwrap();
+        let summary = ThreadSummary {
+            url: state.thread.clone(),
+            dir: dir.map(String::from),
+            status,
+            error,
+            files: state.files,
+            bytes: state.bytes,
+            duration: state.started.map_or(0.0, |s| s.elapsed().as_secs_f64()),
+        };
+        state.threads.push(summary.clone());
+        summary
+    };
+    emit(Event::Thread(summary.clone()));
+    summary
+}
+
+/// Returns the exit code for the threads which are done, see the `EXIT_` constants
+fn exit_code() -> i32 {
+    let state = STATE.lock().unwrap();
+    let failed = state.threads.iter().filter(|t| t.status == "failed").count() as u64
+        + state.threads.iter().map(|t| t.files.failed).sum::<u64>();
+    let succeeded = state.threads.iter().map(|t| t.files.downloaded + t.files.exists).sum::<u64>();
+
+    // Downloads stopped for disk space are a partial failure, even when nothing could be downloaded before
+    if state.threads.iter().any(|t| t.status == "stopped") {
+        EXIT_PARTIAL_FAILURE
+    }
+    else if failed > 0 && succeeded == 0 {
+        EXIT_FAILURE
+    }
+    else if failed > 0 {
+        EXIT_PARTIAL_FAILURE
+    }
+    else if state.threads.iter().any(|t| t.status == "not-found") {
+        EXIT_NOT_FOUND
+    }
+    else {
+        EXIT_SUCCESS
+    }
+}
+
+/// Prints what happened to the files of each thread and in total, or writes the summary event and all events with `--output json`.
+/// Returns the exit code, must be called before exiting
+pub fn finish() -> i32 {
+    let exit_code = exit_code();
+    let (threads, mut files, mut bytes) = {
+        let state = STATE.lock().unwrap();
+        (state.threads.clone(), Files::default(), 0)
+    };
+    for thread in &threads {
+        files.add(thread.files);
+        bytes += thread.bytes;
+    }
+    let duration = STARTED.get().map_or(0.0, |s| s.elapsed().as_secs_f64());
+
+    emit(Event::Summary { threads: threads.len(), files, bytes, duration, exit_code });
+    // Messages are written to standard error with the other outputs, so the summary is shown there as well
+    print_summary(&threads, files, bytes, duration);
+    if output() == Output::Json {
+        let state = STATE.lock().unwrap();
+        println!("{}", serde_json::to_string_pretty(&state.events).unwrap());
+    }
+    exit_code
+}
+
+/// Prints a table of the threads when there is more than one, and the total number of files
+fn print_summary(threads: &[ThreadSummary], files: Files, bytes: u64, duration: f64) {
+    if threads.len() > 1 {
+        statusln!();
+        statusln!("STATUS     NEW SKIPPED FAILED IQDB        SIZE     TIME THREAD");
+        for thread in threads {
+            // Padding is applied before coloring since escape codes would be counted as characters
+            let (color, name) = match (thread.status, &thread.dir, &thread.error) {
+                ("done", Some(dir), _) => (Green, dir.clone()),
+                ("not-found", _, _) => (Yellow, thread.url.clone()),
+                ("over-quota", _, Some(error)) => (Yellow, format!("{}: {}", thread.url, error)),
+                (_, _, Some(error)) => (Red, format!("{}: {}", thread.url, error)),
+                _ => (Red, thread.url.clone()),
+            };
+            statusln!("{} {:>4} {:>7} {:>6} {:>4} {:>8.1} MB {:>7.1}s {}",
+                color.paint(format!("{:<9}", thread.status)),
+                thread.files.downloaded,
+                thread.files.exists,
+                thread.files.failed,
+                thread.files.iqdb_not_found,
+                thread.bytes as f64 / (1024.0 * 1024.0),
+                thread.duration,
+                name);
+        }
+    }
+    statusln!("{} new, {} skipped, {} failed, {} not found on iqdb, {}{:.1} MB downloaded from {} threads in {:.1}s",
+        Green.paint(files.downloaded.to_string()),
+        files.exists,
+        if files.failed > 0 { Red.paint(files.failed.to_string()) } else { Green.paint("0") },
+        files.iqdb_not_found,
+        if files.over_quota > 0 { format!("{} over quota, ", Yellow.paint(files.over_quota.to_string())) } else { String::new() },
+        bytes as f64 / (1024.0 * 1024.0),
+        threads.len(),
+        duration);
+}
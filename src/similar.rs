@@ -0,0 +1,234 @@
+use crate::color::Color::*;
+use image::{imageops::FilterType, DynamicImage};
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::dedupe::{media_files, same_file};
+use crate::THUMBNAIL_DIR;
+
+/// Extensions of the files which can be decoded to compute perceptual hashes
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "gif"];
+
+/// Perceptual hash algorithm, all of them give 64 bit hashes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Compares each pixel of an 8x8 image to the mean
+    Average,
+    /// Compares each pixel of a 9x8 image to its neighbour
+    Difference,
+    /// Compares the low frequencies of the discrete cosine transform of a 32x32 image to the median
+    Perceptual,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ahash" => Ok(Algorithm::Average),
+            "dhash" => Ok(Algorithm::Difference),
+            "phash" => Ok(Algorithm::Perceptual),
+            _ => Err(format!("'{}' is not a hash algorithm, expected ahash, dhash or phash", s)),
+        }
+    }
+}
+
+/// Image in a thread directory with its perceptual hash
+struct HashedImage {
+    path: PathBuf,
+    hash: u64,
+    width: u32,
+    height: u32,
+    size: u64,
+}
+
+/// Returns the perceptual hash of the image
+pub fn hash_image(path: &Path, algorithm: Algorithm) -> Result<u64, String> {
+    let img = image::open(path).map_err(|e| e.to_string())?;
+    Ok(hash(&img, algorithm))
+}
+
+/// Returns the number of bits which differ between two hashes
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn hash(img: &DynamicImage, algorithm: Algorithm) -> u64 {
+    match algorithm {
+        Algorithm::Average => {
+            let pixels = grayscale(img, 8, 8);
+            let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+            bits(pixels.iter().map(|p| *p > mean))
+        },
+        Algorithm::Difference => {
+            let pixels = grayscale(img, 9, 8);
+            bits((0..8).flat_map(|y| (0..8).map(move |x| (x, y)))
+                .map(|(x, y)| pixels[y * 9 + x] < pixels[y * 9 + x + 1]))
+        },
+        Algorithm::Perceptual => {
+            let pixels = grayscale(img, 32, 32);
+            let coefficients = dct(&pixels, 32, 8);
+            // The first coefficient is the average of the image and would dominate the median
+            let mut sorted: Vec<f64> = coefficients[1..].to_vec();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let median = sorted[sorted.len() / 2];
+            bits(coefficients.iter().map(|c| *c > median))
+        },
+    }
+}
+
+/// Resizes the image and returns the brightness of each pixel, row by row
+fn grayscale(img: &DynamicImage, width: u32, height: u32) -> Vec<f64> {
+    img.resize_exact(width, height, FilterType::Triangle)
+        .to_luma8()
+        .pixels()
+        .map(|p| p.0[0] as f64)
+        .collect()
+}
+
+/// Returns the top left `n`x`n` coefficients of the two dimensional discrete cosine transform of a `size`x`size` image
+fn dct(pixels: &[f64], size: usize, n: usize) -> Vec<f64> {
+    let cosines: Vec<Vec<f64>> = (0..n)
+        .map(|u| (0..size)
+            .map(|x| ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * size) as f64).cos())
+            .collect())
+        .collect();
+
+    let mut coefficients = Vec::with_capacity(n * n);
+    for v in 0..n {
+        for u in 0..n {
+            let mut sum = 0.0;
+            for y in 0..size {
+                for x in 0..size {
+                    sum += pixels[y * size + x] * cosines[u][x] * cosines[v][y];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+    coefficients
+}
+
+/// Returns the representative of the group the element is in
+fn root(parents: &mut [usize], i: usize) -> usize {
+    let mut i = i;
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+fn bits<I: Iterator<Item = bool>>(bits: I) -> u64 {
+    bits.fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
+
+/// Prints groups of images in the directories which look alike.
+/// With `keep_best` the image with the highest resolution in each group is kept, and images within the distance of it are removed.
+/// Thumbnails and images kept from iqdb are not compared
+pub fn find_similar(dirs: &[PathBuf], algorithm: Algorithm, max_distance: u32, keep_best: bool, dry_run: bool) {
+    let mut images: Vec<HashedImage> = Vec::new();
+    let files = dirs.iter().flat_map(|d| {
+        let (thumbs, iqdb) = (d.join(THUMBNAIL_DIR), d.join("iqdb"));
+        media_files(d).into_iter().filter(move |p| !p.starts_with(&thumbs) && !p.starts_with(&iqdb))
+    });
+    for path in files {
+        let is_image = path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.as_str()));
+        if !is_image {
+            continue;
+        }
+
+        let img = match image::open(&path) {
+            Ok(img) => img,
+            Err(e) => {
+                crate::debug_output("image error", &format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        images.push(HashedImage {
+            hash: hash(&img, algorithm),
+            width: img.width(),
+            height: img.height(),
+            size: path.metadata().map(|m| m.len()).unwrap_or(0),
+            path,
+        });
+    }
+
+    // Groups images with union-find, where images within the distance of each other are joined.
+    // Links to the same file, such as hard links made by dedupe, are not similar images
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    for a in 0..images.len() {
+        for b in (a + 1)..images.len() {
+            if distance(images[a].hash, images[b].hash) <= max_distance && !same_file(&images[a].path, &images[b].path) {
+                let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                parents[root_b] = root_a;
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root: Vec<Option<usize>> = vec![None; images.len()];
+    for i in 0..images.len() {
+        let r = root(&mut parents, i);
+        match group_of_root[r] {
+            Some(g) => groups[g].push(i),
+            None => {
+                group_of_root[r] = Some(groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+    groups.retain(|g| g.len() > 1);
+
+    let mut removed = 0;
+    for (n, group) in groups.iter_mut().enumerate() {
+        // Highest resolution first, larger files first when the resolution is the same
+        group.sort_by_key(|i| std::cmp::Reverse((images[*i].width as u64 * images[*i].height as u64, images[*i].size)));
+
+        println!("Group {}:", Cyan.paint((n + 1).to_string()));
+        let best = &images[group[0]];
+        for (j, i) in group.iter().enumerate() {
+            let image = &images[*i];
+            let distance = distance(best.hash, image.hash);
+            print!("    {} ({}x{}, {} KB", image.path.display(), image.width, image.height, image.size / 1024);
+            if j > 0 {
+                print!(", distance {}", distance);
+            }
+            print!(")");
+
+            if j == 0 {
+                println!(" {}", Green.paint("best"));
+            }
+            // Groups are joined through images in between, so images which are not close to the best image are kept
+            else if keep_best && distance > max_distance {
+                println!(" {}", Yellow.paint("kept, not similar to the best image"));
+            }
+            else if keep_best && same_file(&best.path, &image.path) {
+                println!(" {}", Yellow.paint("kept, same file as the best image"));
+            }
+            else if keep_best && dry_run {
+                println!(" {}", Blue.paint("(dry run)"));
+            }
+            else if keep_best {
+                match remove_file(&image.path) {
+                    Ok(_) => {
+                        removed += 1;
+                        println!(" {}", Red.paint("removed"));
+                    },
+                    Err(e) => println!(" {} {}", Red.paint("could not be removed:"), e),
+                }
+            }
+            else {
+                println!();
+            }
+        }
+    }
+
+    println!("Found {} groups of similar images in {} images", groups.len(), images.len());
+    if keep_best && !dry_run {
+        println!("Removed {} images", removed);
+    }
+}
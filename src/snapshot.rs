@@ -0,0 +1,159 @@
+use chrono::{TimeZone, Utc};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use crate::api::Thread;
+use crate::media::Media;
+use crate::THUMBNAIL_DIR;
+use crate::posts::{thread_posts, Attachment, SavedPost};
+
+/// Name of the file the snapshot is saved to in the thread directory
+pub const FILE_NAME: &str = "thread.html";
+
+/// Stylesheet inlined in the snapshot so it does not depend on any other site
+const STYLE: &str = "
+body { background: #eef2ff; color: #000; font-family: arial, helvetica, sans-serif; font-size: 10pt; margin: 8px; }
+a { color: #34345c; }
+a:hover { color: #dd0000; }
+h1 { color: #af0a0f; font-family: tahoma, sans-serif; font-size: 20pt; text-align: center; }
+hr { border: none; border-top: 1px solid #b7c5d9; }
+.post { background: #d6daf0; border: 1px solid #b7c5d9; border-left: none; border-top: none; display: table; margin: 4px 0; padding: 4px 8px; }
+.post.op { background: none; border: none; display: block; }
+.post.deleted { opacity: 0.6; }
+.subject { color: #0f0c5d; font-weight: bold; }
+.name { color: #117743; font-weight: bold; }
+.file { margin: 4px 0; }
+.file img, .file video { float: left; margin: 0 20px 4px 0; max-height: 250px; max-width: 250px; }
+.comment { margin: 12px 40px; }
+.quote { color: #789922; }
+.backlinks { font-size: 8pt; }
+.clear { clear: both; }
+";
+
+/// Writes a self-contained HTML page of the thread to `thread.html` in `dir_path`.
+/// Images link to the files downloaded to the thread directory so the page works after the thread is gone
+pub fn save_snapshot(dir_path: &Path, url: &str, board: &str, no: u64, thread: &Thread) {
+    write(dir_path.join(FILE_NAME), snapshot_html(dir_path, url, board, no, thread)).expect("Could not write thread.html");
+}
+
+/// Returns true if the snapshot in `dir_path` differs from one saved now, since posts were added or deleted
+/// or files it links to were downloaded or removed after it was saved
+pub fn is_outdated(dir_path: &Path, url: &str, board: &str, no: u64, thread: &Thread) -> bool {
+    match read_to_string(dir_path.join(FILE_NAME)) {
+        Ok(contents) => contents != snapshot_html(dir_path, url, board, no, thread),
+        Err(_) => true,
+    }
+}
+
+/// Returns the HTML page of the thread, linking to the files which exist in `dir_path`
+fn snapshot_html(dir_path: &Path, url: &str, board: &str, no: u64, thread: &Thread) -> String {
+    let posts = thread_posts(thread);
+    let title = match posts.first().and_then(|p| p.subject.as_ref()) {
+        Some(subject) => format!("/{}/ - {}", board, subject),
+        None => format!("/{}/ - {}", board, no),
+    };
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n", title));
+    html.push_str(&format!("<style>{}</style>\n", STYLE));
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>{}</h1>\n", title));
+    html.push_str(&format!("<p>Snapshot of <a href=\"{0}\">{0}</a></p>\n<hr>\n", escape(url)));
+    for (i, post) in posts.iter().enumerate() {
+        html.push_str(&post_html(dir_path, post, i == 0));
+    }
+    html.push_str("<hr>\n</body>\n</html>\n");
+    html
+}
+
+fn post_html(dir_path: &Path, post: &SavedPost, op: bool) -> String {
+    let mut html = format!("<div class=\"post{}\" id=\"p{}\">\n", if op { " op" } else { "" }, post.no);
+
+    if let Some(attachment) = &post.attachment {
+        html.push_str(&attachment_html(dir_path, attachment));
+    }
+
+    // Name and subject are already escaped by 4chan
+    html.push_str("<div class=\"info\">");
+    if let Some(subject) = &post.subject {
+        html.push_str(&format!("<span class=\"subject\">{}</span> ", subject));
+    }
+    html.push_str(&format!("<span class=\"name\">{}</span>", post.name.as_deref().unwrap_or("Anonymous")));
+    if let Some(tripcode) = &post.tripcode {
+        html.push_str(&format!(" <span class=\"name\">{}</span>", tripcode));
+    }
+    if let Some(poster_id) = &post.poster_id {
+        html.push_str(&format!(" (ID: {})", poster_id));
+    }
+    let time = Utc.timestamp_opt(post.time, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default();
+    html.push_str(&format!(" {} <a href=\"#p{1}\">No.{1}</a>", time, post.no));
+    if post.deleted {
+        html.push_str(" [Deleted]");
+    }
+    html.push_str("</div>\n");
+
+    if !post.replied_by.is_empty() {
+        html.push_str("<div class=\"backlinks\">");
+        for reply in post.replied_by.iter() {
+            html.push_str(&format!("<a href=\"#p{0}\">&gt;&gt;{0}</a> ", reply));
+        }
+        html.push_str("</div>\n");
+    }
+
+    // Links to other threads are relative to the 4chan site
+    let comment = post.comment_html.replace("href=\"/", "href=\"https://boards.4chan.org/");
+    html.push_str(&format!("<blockquote class=\"comment\">{}</blockquote>\n", comment));
+    html.push_str("<div class=\"clear\"></div>\n</div>\n");
+    html
+}
+
+fn attachment_html(dir_path: &Path, attachment: &Attachment) -> String {
+    let mut html = String::from("<div class=\"file\">");
+
+    let size = attachment.size.map(|s| format!("{} KB", s / 1024)).unwrap_or_default();
+    let dimensions = match (attachment.width, attachment.height) {
+        (Some(w), Some(h)) => format!(", {}x{}", w, h),
+        _ => String::new(),
+    };
+    html.push_str(&format!("File: <a href=\"{0}\">{1}</a> ({2}{3})<br>\n",
+        escape(&attachment.file_name),
+        escape(&attachment.original_name),
+        size,
+        dimensions));
+
+    // Thumbnails saved with --thumbnails are used as previews
+    let thumbnail = Media::from_link(&attachment.file_name)
+        .map(|media| format!("{}/{}", THUMBNAIL_DIR, media.thumbnail_name()))
+        .filter(|thumbnail| dir_path.join(thumbnail).is_file());
+
+    if attachment.deleted {
+        html.push_str("File deleted");
+    }
+    else if !dir_path.join(&attachment.file_name).is_file() {
+        html.push_str("File not downloaded");
+    }
+    else if let Some(thumbnail) = thumbnail {
+        html.push_str(&format!("<a href=\"{}\"><img src=\"{}\" loading=\"lazy\"></a>", escape(&attachment.file_name), escape(&thumbnail)));
+    }
+    else if attachment.file_name.ends_with(".webm") || attachment.file_name.ends_with(".mp4") {
+        html.push_str(&format!("<video controls preload=\"none\" src=\"{}\"></video>", escape(&attachment.file_name)));
+    }
+    else {
+        html.push_str(&format!("<a href=\"{0}\"><img src=\"{0}\" loading=\"lazy\"></a>", escape(&attachment.file_name)));
+    }
+
+    html.push_str("</div>\n");
+    html
+}
+
+/// Escapes text for use in HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
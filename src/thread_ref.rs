@@ -0,0 +1,221 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Reference to a thread on 4chan or on an archive site
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadRef {
+    /// Scheme and host of the archive site the thread is on, `None` for 4chan
+    pub archive: Option<String>,
+    pub board: String,
+    pub no: u64,
+}
+
+impl ThreadRef {
+    /// Reads a thread from a link like `https://boards.4chan.org/g/thread/123/subject#p456`,
+    /// a link to an archive site, a `/g/123` shorthand or `g 123`
+    pub fn parse(input: &str) -> Result<ThreadRef, String> {
+        let error = || format!("'{}' is not a thread, expected a link like https://boards.4chan.org/g/thread/123, /g/123 or 'g 123'", input);
+
+        // Cross-board links like >>>/g/123 are written in posts
+        let text = input.trim().trim_start_matches(">>>");
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.len() == 2 {
+            return ThreadRef::from_parts(None, words[0].trim_matches('/'), words[1]).ok_or_else(error);
+        }
+        if words.len() != 1 {
+            return Err(error());
+        }
+
+        // Posts and query strings are part of the thread
+        let text = text.split(['#', '?']).next().unwrap_or_default();
+        let (scheme, path) = match text.split_once("://") {
+            Some((scheme, path)) => (Some(scheme.to_lowercase()), path),
+            None => (None, text),
+        };
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        // Links start with a host, which has a dot unlike boards
+        let is_link = scheme.is_some() || segments.first().is_some_and(|s| s.contains('.'));
+        let (host, segments) = match (is_link, segments.split_first()) {
+            (true, Some((host, segments))) => (Some(host.to_lowercase()), segments),
+            (true, None) => return Err(error()),
+            (false, _) => (None, &segments[..]),
+        };
+        let archive = host.filter(|h| !is_4chan_host(h))
+            .map(|h| format!("{}://{}", scheme.as_deref().unwrap_or("https"), h));
+
+        // Slugs after the thread number are left out
+        let thread = match segments {
+            [board, "thread", no, ..] | [board, "res", no, ..] => ThreadRef::from_parts(archive, board, no),
+            [board, no] if !is_link => ThreadRef::from_parts(None, board, no),
+            _ => None,
+        };
+        thread.ok_or_else(error)
+    }
+
+    /// Returns the thread if the board and thread number are valid
+    fn from_parts(archive: Option<String>, board: &str, no: &str) -> Option<ThreadRef> {
+        if board.is_empty() || board.len() > 10 || !board.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        // Old links end with `/res/123.html`
+        let no = no.trim_end_matches(".html").parse().ok().filter(|n| *n > 0)?;
+        Some(ThreadRef {
+            archive,
+            board: board.to_lowercase(),
+            no,
+        })
+    }
+
+    /// Returns true if the thread is on 4chan rather than an archive site
+    pub fn is_4chan(&self) -> bool {
+        self.archive.is_none()
+    }
+
+    /// Link to the thread, 4channel.org links are given as 4chan.org links which they redirect to
+    pub fn url(&self) -> String {
+        match &self.archive {
+            Some(archive) => format!("{}/{}/thread/{}/", archive, self.board, self.no),
+            None => format!("https://boards.4chan.org/{}/thread/{}", self.board, self.no),
+        }
+    }
+}
+
+impl FromStr for ThreadRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ThreadRef::parse(s)
+    }
+}
+
+impl fmt::Display for ThreadRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}/{}", self.board, self.no)
+    }
+}
+
+/// Returns true for 4chan.org, 4channel.org and their subdomains, but not for other domains ending with their names
+fn is_4chan_host(host: &str) -> bool {
+    ["4chan.org", "4channel.org"].iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// Joins `board` and `number` arguments given as separate arguments like `g 123`, so they can be parsed as one thread
+pub fn join_board_args(args: Vec<String>) -> Vec<String> {
+    let mut joined: Vec<String> = Vec::new();
+    for arg in args {
+        let is_number = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_digit());
+        let previous_is_board = joined.last()
+            .is_some_and(|p| !p.contains(' ') && ThreadRef::parse(p).is_err() && ThreadRef::parse(&format!("{} 1", p)).is_ok());
+        if is_number && previous_is_board {
+            let board = joined.pop().unwrap();
+            joined.push(format!("{} {}", board, arg));
+        }
+        else {
+            joined.push(arg);
+        }
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(board: &str, no: u64) -> ThreadRef {
+        ThreadRef { archive: None, board: board.to_string(), no }
+    }
+
+    fn archived(archive: &str, board: &str, no: u64) -> ThreadRef {
+        ThreadRef { archive: Some(archive.to_string()), ..thread(board, no) }
+    }
+
+    #[test]
+    fn links_to_4chan() {
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123/"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("http://boards.4chan.org/g/thread/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("boards.4chan.org/g/thread/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("  https://BOARDS.4chan.org/G/thread/123  "), Ok(thread("g", 123)));
+    }
+
+    #[test]
+    fn slugs_posts_and_query_strings_are_left_out() {
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123/some-subject"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123#p456"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123/some-subject#p456"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/thread/123?foo=bar#q456"), Ok(thread("g", 123)));
+    }
+
+    #[test]
+    fn links_to_4channel() {
+        let parsed = ThreadRef::parse("https://boards.4channel.org/v/thread/789").unwrap();
+        assert_eq!(parsed, thread("v", 789));
+        assert!(parsed.is_4chan());
+        assert_eq!(parsed.url(), "https://boards.4chan.org/v/thread/789");
+    }
+
+    #[test]
+    fn old_res_links() {
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/res/123.html"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/res/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/res/123.html#p124"), Ok(thread("g", 123)));
+    }
+
+    #[test]
+    fn shorthands() {
+        assert_eq!(ThreadRef::parse("/g/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("g/123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("/g/123/"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse(">>>/g/1"), Ok(thread("g", 1)));
+        assert_eq!(ThreadRef::parse("g 123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("/g/ 123"), Ok(thread("g", 123)));
+        assert_eq!(ThreadRef::parse("3 123"), Ok(thread("3", 123)));
+    }
+
+    #[test]
+    fn links_to_archives() {
+        let parsed = ThreadRef::parse("https://archived.moe/g/thread/123/#456").unwrap();
+        assert_eq!(parsed, archived("https://archived.moe", "g", 123));
+        assert!(!parsed.is_4chan());
+        assert_eq!(parsed.url(), "https://archived.moe/g/thread/123/");
+        assert_eq!(ThreadRef::parse("desuarchive.org/a/thread/5"), Ok(archived("https://desuarchive.org", "a", 5)));
+        assert_eq!(ThreadRef::parse("http://127.0.0.1:8765/g/thread/1/"), Ok(archived("http://127.0.0.1:8765", "g", 1)));
+    }
+
+    #[test]
+    fn hosts_ending_with_4chan_are_archives() {
+        assert_eq!(ThreadRef::parse("https://fake4chan.org/g/thread/123"), Ok(archived("https://fake4chan.org", "g", 123)));
+        assert_eq!(ThreadRef::parse("https://not4channel.org/g/thread/123"), Ok(archived("https://not4channel.org", "g", 123)));
+        assert_eq!(ThreadRef::parse("https://4chan.org/g/thread/123"), Ok(thread("g", 123)));
+    }
+
+    #[test]
+    fn display_is_the_shorthand() {
+        assert_eq!(ThreadRef::parse("https://archived.moe/g/thread/123").unwrap().to_string(), "/g/123");
+        assert_eq!("g 123".parse::<ThreadRef>().unwrap().to_string(), "/g/123");
+    }
+
+    #[test]
+    fn invalid_input() {
+        for input in ["", "g", "123", "g abc", "g 0", "g 123 456", "/g/", "https://boards.4chan.org/",
+                      "https://boards.4chan.org/g/", "https://boards.4chan.org/g/123", "https://boards.4chan.org/g/catalog",
+                      "https://boards.4chan.org/g/thread/abc", "/g-b/123", "/abcdefghijk/123", "https://example.com"] {
+            assert!(ThreadRef::parse(input).is_err(), "'{}' should not be a thread", input);
+        }
+        assert_eq!(ThreadRef::parse("https://boards.4chan.org/g/catalog"),
+            Err("'https://boards.4chan.org/g/catalog' is not a thread, expected a link like https://boards.4chan.org/g/thread/123, /g/123 or 'g 123'".to_string()));
+    }
+
+    #[test]
+    fn board_and_number_arguments_are_joined() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(join_board_args(args(&["g", "123"])), args(&["g 123"]));
+        assert_eq!(join_board_args(args(&["/g/", "123", "v", "456"])), args(&["/g/ 123", "v 456"]));
+        assert_eq!(join_board_args(args(&["/g/123", "456"])), args(&["/g/123", "456"]));
+        assert_eq!(join_board_args(args(&["https://boards.4chan.org/g/thread/1", "2"])), args(&["https://boards.4chan.org/g/thread/1", "2"]));
+        assert_eq!(join_board_args(args(&["g", "123", "456"])), args(&["g 123", "456"]));
+        assert_eq!(join_board_args(args(&["g"])), args(&["g"]));
+    }
+}
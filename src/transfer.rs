@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::filter::Filters;
+use crate::watchlist::WatchedThread;
+
+/// Format of an exported watch list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Same format as `threads.json`
+    Json,
+    /// One `url,dir,paused,last_update,filters` row per thread with a header, filters are JSON
+    Csv,
+    /// One url per line, the directory is named by the template when importing
+    Urls,
+    /// One `{url};{directory}` line per thread as in `threads.txt`, only used for importing
+    Legacy,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "urls" => Ok(Format::Urls),
+            "legacy" => Ok(Format::Legacy),
+            _ => Err(format!("'{}' is not a watch list format, expected json, csv, urls or legacy", s)),
+        }
+    }
+}
+
+/// Row of a CSV watch list
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvThread {
+    url: String,
+    #[serde(default)]
+    dir: String,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    last_update: Option<i64>,
+    /// Filters as JSON, empty if the thread has no filters
+    #[serde(default)]
+    filters: String,
+}
+
+/// Returns the format of a watch list from its contents
+pub fn detect(contents: &str) -> Format {
+    let first_line = contents.lines().map(|l| l.trim()).find(|l| !l.is_empty()).unwrap_or_default();
+    if first_line.starts_with('[') {
+        Format::Json
+    }
+    else if first_line.starts_with("url,") {
+        Format::Csv
+    }
+    else if first_line.contains(';') {
+        Format::Legacy
+    }
+    else {
+        Format::Urls
+    }
+}
+
+/// Returns the watch list in the given format
+pub fn export(threads: &[WatchedThread], format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(threads).unwrap(),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for thread in threads {
+                writer.serialize(CsvThread {
+                    url: thread.url.clone(),
+                    dir: thread.dir.clone(),
+                    paused: thread.paused,
+                    last_update: thread.last_update,
+                    filters: if thread.filters.is_empty() { String::new() } else { serde_json::to_string(&thread.filters).unwrap() },
+                }).expect("Could not write CSV");
+            }
+            String::from_utf8(writer.into_inner().expect("Could not write CSV")).unwrap()
+        },
+        Format::Urls => threads.iter().map(|t| format!("{}\n", t.url)).collect(),
+        Format::Legacy => threads.iter().map(|t| format!("{};{}\n", t.url, t.dir)).collect(),
+    }
+}
+
+/// Reads threads from a watch list in the given format.
+/// Threads read from a list of urls have an empty directory
+pub fn parse(contents: &str, format: Format) -> Result<Vec<WatchedThread>, String> {
+    match format {
+        Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        Format::Csv => csv::Reader::from_reader(contents.as_bytes())
+            .deserialize::<CsvThread>()
+            .map(|row| {
+                let row = row.map_err(|e| e.to_string())?;
+                let filters: Filters = if row.filters.is_empty() {
+                    Filters::default()
+                }
+                else {
+                    serde_json::from_str(&row.filters).map_err(|e| format!("Invalid filters of {}: {}", row.url, e))?
+                };
+                Ok(WatchedThread {
+                    filters,
+                    paused: row.paused,
+                    last_update: row.last_update,
+                    ..WatchedThread::new(row.url, row.dir)
+                })
+            })
+            .collect(),
+        Format::Urls => Ok(contents.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| WatchedThread::new(l.to_string(), String::new()))
+            .collect()),
+        Format::Legacy => crate::watchlist::parse_legacy(contents),
+    }
+}
+
+/// Adds the imported threads which are not watched yet, threads are the same if they have the same board and thread number.
+/// Returns the number of added threads
+pub fn merge(threads: &mut Vec<WatchedThread>, imported: Vec<WatchedThread>) -> usize {
+    let mut added = 0;
+    for thread in imported {
+        if !threads.iter().any(|t| t.key() == thread.key()) {
+            threads.push(thread);
+            added += 1;
+        }
+    }
+    added
+}
@@ -0,0 +1,170 @@
+use crate::color::Color::*;
+use chrono::{Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, rename, write};
+use std::io;
+use std::path::Path;
+
+use crate::filter::Filters;
+use crate::thread_ref::ThreadRef;
+
+/// File in the root holding the threads watched by `update`
+pub const FILE_NAME: &str = "threads.json";
+/// Watch list used by earlier versions, with one `{url};{directory}` line per thread
+pub const LEGACY_FILE_NAME: &str = "threads.txt";
+
+/// Thread in the watch list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchedThread {
+    pub url: String,
+    /// Directory images are saved to
+    pub dir: String,
+    #[serde(default, skip_serializing_if = "Filters::is_empty")]
+    pub filters: Filters,
+    /// Paused threads are skipped by `update`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// Unix time of the last download from the thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_update: Option<i64>,
+    /// `Last-Modified` date of the thread page when all files were downloaded, sent with `If-Modified-Since` by `update`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl WatchedThread {
+    pub fn new(url: String, dir: String) -> WatchedThread {
+        WatchedThread {
+            url,
+            dir,
+            filters: Filters::default(),
+            paused: false,
+            last_update: None,
+            last_modified: None,
+        }
+    }
+
+    /// Identifies the thread by board and thread number, so links to archives of the same thread are the same thread
+    pub fn key(&self) -> String {
+        match ThreadRef::parse(&self.url) {
+            Ok(thread) => thread.to_string(),
+            Err(_) => self.url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Thread number from the url, `None` if the url is not a link to a thread
+    pub fn no(&self) -> Option<u64> {
+        ThreadRef::parse(&self.url).ok().map(|t| t.no)
+    }
+}
+
+/// Returns all threads in the watch list.
+/// Threads are read from `threads.txt` if the watch list does not exist yet, they are moved to `threads.json` when saved
+pub fn load() -> Vec<WatchedThread> {
+    let root = crate::config::root();
+    let threads: Vec<WatchedThread> = match read_to_string(root.join(FILE_NAME)) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("{} Could not read {}: {}", Red.paint("Error:"), FILE_NAME, e);
+            std::process::exit(1);
+        }),
+        Err(_) if root.join(LEGACY_FILE_NAME).is_file() => {
+            statusln!("Moving watched threads from {} to {}", LEGACY_FILE_NAME, FILE_NAME);
+            // An empty list would be saved over the legacy list, so threads which cannot be read stop the command
+            read_legacy(root.join(LEGACY_FILE_NAME)).unwrap_or_else(|e| {
+                eprintln!("{} Could not read {}: {}", Red.paint("Error:"), LEGACY_FILE_NAME, e);
+                std::process::exit(1);
+            })
+        },
+        Err(_) => {
+            statusln!("{} does not exist, creating {}", FILE_NAME, Green.paint("Done"));
+            save(&[]);
+            Vec::new()
+        }
+    };
+    for thread in &threads {
+        if let Err(e) = thread.filters.validate() {
+            eprintln!("{} Could not read the filters of {} in {}: {}", Red.paint("Error:"), thread.url, FILE_NAME, e);
+            std::process::exit(1);
+        }
+    }
+    threads
+}
+
+//BUG: When saving to file that has been updated those updates are lost. Read from file first and include the new records
+/// Removes existing watch list and writes the given threads to it
+pub fn save(threads: &[WatchedThread]) {
+    try_save(threads).expect("Could not write watch list");
+}
+
+/// Writes the given threads to the watch list. The list is written to a temporary file first,
+/// so the watch list is either replaced completely or not at all
+pub fn try_save(threads: &[WatchedThread]) -> io::Result<()> {
+    let path = crate::config::root().join(FILE_NAME);
+    let tmp = path.with_extension("json.tmp");
+    write(&tmp, serde_json::to_string_pretty(threads).unwrap())?;
+    rename(&tmp, &path)
+}
+
+/// Returns the index of the thread with the given url, thread number, `/board/number` or directory
+pub fn position(threads: &[WatchedThread], thread: &str) -> Option<usize> {
+    let key = ThreadRef::parse(thread).map(|t| t.to_string());
+    let thread = thread.trim_end_matches('/');
+    threads.iter().position(|t| t.url.trim_end_matches('/') == thread)
+        .or_else(|| thread.parse::<u64>().ok().and_then(|no| threads.iter().position(|t| t.no() == Some(no))))
+        .or_else(|| key.ok().and_then(|key| threads.iter().position(|t| t.key() == key)))
+        .or_else(|| threads.iter().position(|t| t.dir == thread))
+}
+
+/// Returns all threads in a watch list with the legacy `{url};{directory}` format
+pub fn read_legacy<P: AsRef<Path>>(path: P) -> Result<Vec<WatchedThread>, String> {
+    let contents = read_to_string(&path).map_err(|e| e.to_string())?;
+    parse_legacy(&contents)
+}
+
+/// Returns all threads in the legacy `{url};{directory}` format
+pub fn parse_legacy(contents: &str) -> Result<Vec<WatchedThread>, String> {
+    contents
+        // Remove whitespace and special characters
+        .split('\n').map(|s| s.trim()).filter(|s| !s.is_empty())
+        // Convert format to thread
+        .map(|s| match s.split_once(';') {
+            Some((url, dir)) => Ok(WatchedThread::new(url.to_string(), dir.to_string())),
+            None => Err(format!("Could not split '{}'", s)),
+        })
+        .collect()
+}
+
+/// Returns the current unix time, used for the last update of threads
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+/// Prints all watched threads with their status, number of files, last update and disk usage
+pub fn print(threads: &[WatchedThread]) {
+    let root = crate::config::root();
+    println!("STATUS    FILES        SIZE LAST UPDATE      THREAD");
+    for thread in threads {
+        let dir_path = root.join(&thread.dir);
+        let (color, status) = if thread.paused { (Yellow, "paused") }
+            else if !dir_path.is_dir() { (Red, "missing") }
+            else { (Green, "watching") };
+        // Thumbnails are not counted as downloaded files
+        let files = crate::dedupe::media_files(&dir_path).iter()
+            .filter(|f| !f.starts_with(dir_path.join(crate::THUMBNAIL_DIR)))
+            .count();
+        let last_update = thread.last_update
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string());
+
+        // Padding is applied before coloring since escape codes would be counted as characters
+        println!("{} {:>6} {:>8.1} MB {:<16} {}",
+            color.paint(format!("{:<8}", status)),
+            files,
+            crate::quota::dir_size(&dir_path) as f64 / (1024.0 * 1024.0),
+            last_update,
+            thread.url);
+        println!("{}{}", " ".repeat(45), Cyan.paint(format!("{}/", thread.dir)));
+    }
+    println!("{} threads, {} paused", threads.len(), threads.iter().filter(|t| t.paused).count());
+}